@@ -1,6 +1,13 @@
-use super::state::{ApplicationState, CursorMovement, LogMessage, MessageType, ScrollMovement};
+use super::commands::Command;
+use super::config::Config;
+use super::export::{self, JsonLog, RosterEntry};
+use super::state::{
+    ApplicationState, CursorMovement, LogMessage, MessageRate, MessageType, Peer, PeerLimit,
+    PeerState, ScrollMovement, SystemMessageType,
+};
 use super::terminal_events::TerminalEventCollector;
 use super::ui::{self};
+use super::util;
 
 use crossterm::event::{Event as TermEvent, KeyCode, KeyEvent, KeyModifiers};
 use crossterm::{
@@ -12,23 +19,91 @@ use tui::backend::CrosstermBackend;
 use tui::Terminal;
 
 use message_io::events::EventQueue;
-use message_io::network::{NetEvent, NetworkManager};
+use message_io::network::{Endpoint, NetEvent, NetworkManager};
 
 use serde::{Deserialize, Serialize};
 
+use std::collections::HashSet;
 use std::io::{self, Stdout};
-use std::net::SocketAddr;
+use std::net::{SocketAddr, TcpStream};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+const MIN_RENDER_INTERVAL: u64 = 33; //ms, ~30 fps
+const SELF_TEST_TIMEOUT: u64 = 2; //seconds
+const ERROR_FLASH_DURATION: u64 = 200; //ms
+const LOOPBACK_USER: &str = "self";
+const PRESENCE_REFRESH_INTERVAL: u64 = 5; //seconds
+const DISCOVERY_RETRY_INTERVAL: u64 = 10; //seconds
+const MAX_DISCOVERY_RETRIES: usize = 5;
+const MAX_RENDER_FAILURES: usize = 10; // Consecutive failures before giving up
+const OFFLINE_DELIVERY_BATCH: usize = 10; // Within the default message rate of the peers
+const OFFLINE_DELIVERY_INTERVAL: u64 = 1000; //ms
+
+/// Sent in the hello messages. The applications not sending it are taken as version 0,
+/// that only understands HelloLan, HelloUser and UserMessage.
+const PROTOCOL_VERSION: u16 = 1;
 
 #[derive(Serialize, Deserialize)]
 enum NetMessage {
-    HelloLan(String, u16), // user_name, server_port
-    HelloUser(String),     // user_name
-    UserMessage(String),   // content
+    // user_name, server_port, protocol version, repeated (already announced before)
+    HelloLan(
+        #[serde(deserialize_with = "util::deserialize_lossy_string")] String,
+        u16,
+        #[serde(deserialize_with = "util::deserialize_trailing")] u16,
+        #[serde(deserialize_with = "util::deserialize_trailing")] bool,
+    ),
+    // user_name, protocol version, server_port
+    HelloUser(
+        #[serde(deserialize_with = "util::deserialize_lossy_string")] String,
+        #[serde(deserialize_with = "util::deserialize_trailing")] u16,
+        #[serde(deserialize_with = "util::deserialize_trailing")] Option<u16>,
+    ),
+    // content
+    UserMessage(#[serde(deserialize_with = "util::deserialize_lossy_string")] String),
+    // content, only sent to the recipient
+    PrivateMessage(#[serde(deserialize_with = "util::deserialize_lossy_string")] String),
+    // status text, empty if cleared
+    Status(#[serde(deserialize_with = "util::deserialize_lossy_string")] String),
+    // message of the day, sent after HelloUser to a user that just joined
+    Motd(#[serde(deserialize_with = "util::deserialize_lossy_string")] String),
+}
+
+impl NetMessage {
+    /// Version of the protocol that added the message.
+    /// A peer of an older version can not decode it,
+    /// and message-io 0.4 panics its network thread when it happens.
+    fn version(&self) -> u16 {
+        match self {
+            NetMessage::HelloLan(..) | NetMessage::HelloUser(..) | NetMessage::UserMessage(_) => 0,
+            NetMessage::PrivateMessage(_) | NetMessage::Status(_) | NetMessage::Motd(_) => 1,
+        }
+    }
+
+    /// Name and approximated size, shown in debug mode.
+    fn summary(&self) -> (&'static str, usize) {
+        match self {
+            NetMessage::HelloLan(user, ..) => ("HelloLan", user.len() + 5),
+            NetMessage::HelloUser(user, ..) => ("HelloUser", user.len() + 5),
+            NetMessage::UserMessage(content) => ("UserMessage", content.len()),
+            NetMessage::PrivateMessage(content) => ("PrivateMessage", content.len()),
+            NetMessage::Status(status) => ("Status", status.len()),
+            NetMessage::Motd(motd) => ("Motd", motd.len()),
+        }
+    }
 }
 
 enum Event {
     Network(NetEvent<NetMessage>),
     Terminal(TermEvent),
+    IdleCheck,
+    DiscoveryRetry(usize), // attempt
+    PresenceRefresh,
+    SelfTestTimeout(Instant),               // start of the self test
+    OfflineDelivery(Endpoint, Vec<String>), // messages still to deliver
+    PeerClosed(Endpoint),
+    Render,
+    ErrorFlashEnd,
     Close,
 }
 
@@ -37,17 +112,38 @@ pub struct Application {
     network: NetworkManager,
     terminal: Terminal<CrosstermBackend<Stdout>>,
     _terminal_events: TerminalEventCollector,
-    discovery_addr: SocketAddr,
-    user_name: String,
+    config: Config,
+    server_addr: SocketAddr,
+    discovery_listener: Option<usize>, // resource id
+    discovery_endpoint: Option<Endpoint>,
+    loopback_endpoint: Option<Endpoint>, // Its messages are processed locally instead of sent
+    pending_self_test: Option<Instant>,  // Waiting for the own announcement since then
+    status: Option<String>,              // Own status, sent to every user
+    shown_motds: HashSet<String>,        // The same message of the day is only shown once
+    json_log: Option<JsonLog>,
+    json_logged_messages: usize, // Messages of the state already written to the JSON log
+    state: ApplicationState,
+    last_user_activity: Instant,
+    render_failures: usize,
 }
 
 impl Application {
-    pub fn new(discovery_addr: SocketAddr, user_name: &str) -> io::Result<Application> {
+    pub fn new(config: Config) -> io::Result<Application> {
         let mut event_queue = EventQueue::new();
 
         let sender = event_queue.sender().clone(); // Collect network events
-        let network = NetworkManager::new(move |net_event| sender.send(Event::Network(net_event)));
+        let mut network =
+            NetworkManager::new(move |net_event| sender.send(Event::Network(net_event)));
 
+        let server_addr = Self::listen_tcp_in_range(&mut network, config.tcp_ports.clone())?;
+
+        let json_log = match &config.json_log {
+            Some(path) => Some(JsonLog::open(path, &config).map_err(|err| {
+                let context = format!("can not open the JSON log {}: {}", path.display(), err);
+                io::Error::new(err.kind(), context)
+            })?),
+            None => None,
+        };
         let sender = event_queue.sender().clone(); // Collect terminal events
         let _terminal_events =
             TerminalEventCollector::new(move |term_event| sender.send(Event::Terminal(term_event)));
@@ -58,132 +154,1098 @@ impl Application {
             .unwrap();
         let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
 
+        let mut state = ApplicationState::new();
+        if config.show_users_panel {
+            state.toggle_users_panel();
+        }
+        if config.activity_panel.is_some() {
+            state.enable_activity_panel();
+        }
+        if config.compact_layout {
+            state.toggle_message_layout();
+        }
+        for (user, alias) in &config.aliases {
+            state.set_alias(user.clone(), Some(alias.clone()));
+        }
+
+        let status = config.status.clone();
         Ok(Application {
             event_queue,
             network,
             terminal,
             // Stored because we want its internal thread functionality until the Application was dropped
             _terminal_events,
-            discovery_addr,
-            user_name: user_name.into(),
+            config,
+            server_addr,
+            discovery_listener: None,
+            discovery_endpoint: None,
+            loopback_endpoint: None,
+            pending_self_test: None,
+            status,
+            shown_motds: HashSet::new(),
+            json_log,
+            json_logged_messages: 0,
+            state,
+            last_user_activity: Instant::now(),
+            render_failures: 0,
         })
     }
 
-    pub fn run(&mut self) {
-        let mut state = ApplicationState::new();
-        ui::draw(&mut self.terminal, &state);
+    /// Listens on the first free port of the range, returning the address actually bound.
+    /// That port is the one announced to others, as the range may not start with it.
+    fn listen_tcp_in_range(
+        network: &mut NetworkManager,
+        ports: RangeInclusive<u16>,
+    ) -> io::Result<SocketAddr> {
+        let (first_port, last_port) = (*ports.start(), *ports.end());
+        for port in ports {
+            match network.listen_tcp(("0.0.0.0", port)) {
+                Ok((_, server_addr)) => return Ok(server_addr),
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse && port < last_port => (),
+                Err(err) if first_port == last_port => return Err(err),
+                Err(err) => {
+                    let context = format!("no free port in {}-{}: {}", first_port, last_port, err);
+                    return Err(io::Error::new(err.kind(), context));
+                }
+            }
+        }
+        unreachable!("the range of ports is never empty")
+    }
 
-        let (_, server_addr) = self.network.listen_tcp("0.0.0.0:0").unwrap();
-        let server_port = server_addr.port();
+    pub fn run(&mut self) -> io::Result<()> {
+        self.render()?;
 
-        self.network
-            .listen_udp_multicast(self.discovery_addr)
-            .unwrap();
+        self.retry_discovery(0);
 
-        let discovery_endpoint = self.network.connect_udp(self.discovery_addr).unwrap();
-        self.network
-            .send(
-                discovery_endpoint,
-                NetMessage::HelloLan(self.user_name.clone(), server_port),
-            )
-            .unwrap();
+        if self.config.loopback {
+            self.start_loopback();
+        }
+
+        if let Some(idle_timeout) = self.config.idle_timeout {
+            self.event_queue
+                .sender()
+                .send_with_timer(Event::IdleCheck, idle_timeout);
+        }
+
+        // Users become idle without any event, so the users panel is redrawn periodically
+        self.event_queue.sender().send_with_timer(
+            Event::PresenceRefresh,
+            Duration::from_secs(PRESENCE_REFRESH_INTERVAL),
+        );
+
+        let render_interval = Duration::from_millis(MIN_RENDER_INTERVAL);
+        let mut last_render = Instant::now();
+        let mut render_scheduled = false;
+        let mut error_flash_scheduled = false;
 
         loop {
-            match self.event_queue.receive() {
-                Event::Network(net_event) => match net_event {
-                    NetEvent::Message(endpoint, message) => match message {
-                        // by udp (multicast):
-                        NetMessage::HelloLan(user, server_port) => {
-                            let server_addr = (endpoint.addr().ip(), server_port);
-                            if user != self.user_name {
-                                let user_endpoint = self.network.connect_tcp(server_addr).unwrap();
-                                self.network
-                                    .send(
-                                        user_endpoint,
-                                        NetMessage::HelloUser(self.user_name.clone()),
-                                    )
-                                    .unwrap();
-                                state.connected_user(user_endpoint, &user);
-                            }
-                        }
-                        // by tcp:
-                        NetMessage::HelloUser(user) => {
-                            state.connected_user(endpoint, &user);
+            let event = self.event_queue.receive();
+            // Network events can arrive at a high rate, so their renders are coalesced.
+            // Terminal events are rendered instantly to keep the input responsive.
+            let throttled = matches!(event, Event::Network(_));
+            match event {
+                Event::Network(net_event) => self.process_network_event(net_event),
+                Event::Terminal(term_event) => self.process_terminal_event(term_event),
+                Event::IdleCheck => self.check_idle(),
+                Event::DiscoveryRetry(attempt) => self.retry_discovery(attempt),
+                Event::PresenceRefresh => {
+                    self.event_queue.sender().send_with_timer(
+                        Event::PresenceRefresh,
+                        Duration::from_secs(PRESENCE_REFRESH_INTERVAL),
+                    );
+                }
+                Event::SelfTestTimeout(start) => {
+                    if self.pending_self_test == Some(start) {
+                        self.pending_self_test = None;
+                        let warning = "No multicast: the own discovery message did not arrive, \
+                            check the firewall or the network interface";
+                        self.state
+                            .add_system_message(warning.into(), SystemMessageType::Warning);
+                    }
+                }
+                Event::OfflineDelivery(endpoint, messages) => {
+                    self.deliver_offline_messages(endpoint, messages)
+                }
+                Event::PeerClosed(endpoint) => self.state.disconnected_user(endpoint),
+                Event::Render => render_scheduled = false,
+                Event::ErrorFlashEnd => {
+                    self.state.end_error_flash();
+                    error_flash_scheduled = false;
+                }
+                Event::Close => break,
+            }
+
+            self.write_json_log();
+
+            if self.state.error_flash() && !error_flash_scheduled {
+                self.event_queue.sender().send_with_timer(
+                    Event::ErrorFlashEnd,
+                    Duration::from_millis(ERROR_FLASH_DURATION),
+                );
+                error_flash_scheduled = true;
+            }
+
+            let since_last_render = last_render.elapsed();
+            if throttled && since_last_render < render_interval {
+                if !render_scheduled {
+                    self.event_queue
+                        .sender()
+                        .send_with_timer(Event::Render, render_interval - since_last_render);
+                    render_scheduled = true;
+                }
+                continue;
+            }
+            self.render()?;
+            last_render = Instant::now();
+        }
+        self.write_json_log();
+        Ok(())
+    }
+
+    /// Writes the messages added since the last call to the JSON log, if any.
+    fn write_json_log(&mut self) {
+        let json_log = match &mut self.json_log {
+            Some(json_log) => json_log,
+            None => return,
+        };
+        let messages = &self.state.messages()[self.json_logged_messages..];
+        let result = json_log.write(messages);
+        self.json_logged_messages = self.state.messages().len();
+        if let Err(err) = result {
+            self.json_log = None;
+            let error = format!("The JSON log was stopped: {}", err);
+            self.state
+                .add_system_message(error, SystemMessageType::Error);
+        }
+    }
+
+    /// Draws the UI. A failure (e.g. a resize race) only skips that frame,
+    /// the error is returned if the terminal keeps failing.
+    fn render(&mut self) -> io::Result<()> {
+        match ui::draw(&mut self.terminal, &self.state, &self.config) {
+            Ok(()) => self.render_failures = 0,
+            Err(err) => {
+                self.render_failures += 1;
+                if self.render_failures >= MAX_RENDER_FAILURES {
+                    return Err(err);
+                }
+                if self.render_failures == 1 {
+                    let warning = format!("Could not draw the screen: {}", err);
+                    self.state
+                        .add_system_message(warning, SystemMessageType::Warning);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn hello_lan(&self, repeated: bool) -> NetMessage {
+        let user = self.config.user_name.clone();
+        NetMessage::HelloLan(user, self.server_addr.port(), PROTOCOL_VERSION, repeated)
+    }
+
+    /// Joins the discovery multicast group and announces this application on it.
+    /// Returns the listener resource id and the endpoint to send to the group.
+    fn start_discovery(&mut self) -> io::Result<(usize, Endpoint)> {
+        let discovery_addr = self.config.discovery_addr;
+        let add_context = |err: io::Error, context: &str| {
+            io::Error::new(
+                err.kind(),
+                format!("{} {}: {}", context, discovery_addr, err),
+            )
+        };
+
+        // message-io binds the discovery socket with SO_REUSEADDR,
+        // so several instances in the same host can listen the same multicast group.
+        let (discovery_listener, _) = self
+            .network
+            .listen_udp_multicast(discovery_addr)
+            .map_err(|err| add_context(err, "can not listen"))?;
+
+        let discovery_endpoint = match self.network.connect_udp(discovery_addr) {
+            Ok(discovery_endpoint) => discovery_endpoint,
+            Err(err) => {
+                self.network.remove_resource(discovery_listener);
+                return Err(add_context(err, "can not connect to"));
+            }
+        };
+
+        if let Err(err) = self.network.send(discovery_endpoint, self.hello_lan(false)) {
+            self.network.remove_resource(discovery_listener);
+            self.network
+                .remove_resource(discovery_endpoint.resource_id());
+            return Err(add_context(err, "can not send to"));
+        }
+
+        Ok((discovery_listener, discovery_endpoint))
+    }
+
+    /// Starts the discovery, retrying on a timer a bounded number of times if it fails.
+    /// Only the first failure and the last one are reported, the chat is usable meanwhile.
+    fn retry_discovery(&mut self, attempt: usize) {
+        if self.discovery_endpoint.is_some() {
+            return; // Already started by changing the group
+        }
+        let result = self
+            .start_discovery()
+            .map(|(discovery_listener, discovery_endpoint)| {
+                self.discovery_listener = Some(discovery_listener);
+                self.discovery_endpoint = Some(discovery_endpoint);
+            });
+        if let Some(delay) = report_discovery(&mut self.state, attempt, &result) {
+            self.event_queue
+                .sender()
+                .send_with_timer(Event::DiscoveryRetry(attempt + 1), delay);
+        }
+    }
+
+    /// Leaves the current discovery group, disconnecting its users, and joins the new one.
+    fn change_discovery_group(&mut self, discovery_addr: SocketAddr) {
+        if let Some(discovery_listener) = self.discovery_listener.take() {
+            self.network.remove_resource(discovery_listener);
+        }
+        if let Some(discovery_endpoint) = self.discovery_endpoint.take() {
+            self.network
+                .remove_resource(discovery_endpoint.resource_id());
+        }
+
+        // The loopback user is kept, with the side of its connection accepted by the listener
+        let loopback_endpoint = self.loopback_endpoint;
+        let loopback_accepted_addr = loopback_endpoint
+            .and_then(|endpoint| self.network.local_address(endpoint.resource_id()));
+        let peers = self
+            .state
+            .all_peer_endpoints()
+            .copied()
+            .filter(|endpoint| Some(*endpoint) != loopback_endpoint)
+            .filter(|endpoint| Some(endpoint.addr()) != loopback_accepted_addr)
+            .collect::<Vec<_>>();
+        for endpoint in peers {
+            // Removing the users also allows to report the peer limit again
+            self.close_peer(endpoint);
+        }
+
+        self.config.discovery_addr = discovery_addr;
+        match self.start_discovery() {
+            Ok((discovery_listener, discovery_endpoint)) => {
+                self.discovery_listener = Some(discovery_listener);
+                self.discovery_endpoint = Some(discovery_endpoint);
+                let info = format!("Joined the discovery group {}", discovery_addr);
+                self.state.add_system_message(info, SystemMessageType::Info);
+            }
+            Err(err) => {
+                let error = format!("Could not join the discovery group: {}", err);
+                self.state
+                    .add_system_message(error, SystemMessageType::Error);
+            }
+        }
+    }
+
+    /// Adds a synthetic user whose received messages are the ones sent by this application.
+    /// It is backed by a connection to the own listener, only used to identify the user,
+    /// so the messages go through the whole receiving process without a second application.
+    fn start_loopback(&mut self) {
+        let local_server_addr = SocketAddr::from(([127, 0, 0, 1], self.server_addr.port()));
+        match self.network.connect_tcp(local_server_addr) {
+            Ok(endpoint) => {
+                self.loopback_endpoint = Some(endpoint);
+                let peer = Peer {
+                    user: LOOPBACK_USER.into(),
+                    server_port: None,
+                    version: PROTOCOL_VERSION,
+                };
+                self.connect_user(endpoint, peer);
+            }
+            Err(err) => {
+                let warning = format!("Could not start the loopback user: {}", err);
+                self.state
+                    .add_system_message(warning, SystemMessageType::Warning);
+            }
+        }
+    }
+
+    fn process_network_event(&mut self, net_event: NetEvent<NetMessage>) {
+        // There is no event for data that can not be deserialized:
+        // message-io 0.4 unwraps the decoding, so it panics its network thread instead.
+        // It can not be caught here without upgrading message-io.
+        if self.state.debug() {
+            let info = match &net_event {
+                NetEvent::Message(endpoint, message) => {
+                    let (name, size) = message.summary();
+                    format!("{} from {} ({} bytes)", name, endpoint.addr(), size)
+                }
+                NetEvent::AddedEndpoint(endpoint) => format!("Added endpoint {}", endpoint.addr()),
+                NetEvent::RemovedEndpoint(endpoint) => {
+                    format!("Removed endpoint {}", endpoint.addr())
+                }
+            };
+            self.state.add_system_message(info, SystemMessageType::Info);
+        }
+
+        match net_event {
+            NetEvent::Message(endpoint, message) => {
+                if let Some(PeerState::Disconnecting) = self.state.peer_state(endpoint) {
+                    return;
+                }
+                self.process_network_message(endpoint, message);
+            }
+            NetEvent::AddedEndpoint(endpoint) => self.state.pending_peer(endpoint),
+            NetEvent::RemovedEndpoint(endpoint) => self.remove_peer(endpoint),
+        }
+    }
+
+    fn remove_peer(&mut self, endpoint: Endpoint) {
+        let offline_buffer = self.config.offline_buffer_ttl.is_some();
+        self.state.remove_peer(endpoint, offline_buffer);
+    }
+
+    /// Closes the connection from here. Its events already queued are ignored
+    /// until the `PeerClosed` event, queued after them, forgets the endpoint.
+    fn close_peer(&mut self, endpoint: Endpoint) {
+        self.network.remove_resource(endpoint.resource_id());
+        let offline_buffer = self.config.offline_buffer_ttl.is_some();
+        self.state.disconnecting_peer(endpoint, offline_buffer);
+        self.event_queue.sender().send(Event::PeerClosed(endpoint));
+    }
+
+    /// Checks the configured maximum of connected users, warning the first time it is reached.
+    fn is_peer_limit_reached(&mut self) -> bool {
+        let max_peers = match self.config.max_peers {
+            Some(max_peers) => max_peers,
+            None => return false,
+        };
+        match self.state.peer_limit(max_peers, self.loopback_endpoint) {
+            PeerLimit::Available => false,
+            PeerLimit::Full => true,
+            PeerLimit::Reached => {
+                let warning = format!(
+                    "Limit of {} users reached, new users are ignored until someone leaves",
+                    max_peers
+                );
+                self.state
+                    .add_system_message(warning, SystemMessageType::Warning);
+                true
+            }
+        }
+    }
+
+    fn process_network_message(&mut self, endpoint: Endpoint, message: NetMessage) {
+        self.state.peer_activity(endpoint);
+        match message {
+            // by udp (multicast):
+            NetMessage::HelloLan(user, server_port, version, repeated) => {
+                let server_addr = SocketAddr::new(endpoint.addr().ip(), server_port);
+                let user = match util::sanitize_user_name(&user)
+                    .filter(|_| self.config.is_peer_allowed(endpoint.addr().ip()))
+                {
+                    Some(user) => user,
+                    None => return self.state.count_rejected_peer(),
+                };
+                // Our own announcement also arrives through the multicast group
+                let own_announcement =
+                    user == self.config.user_name && server_port == self.server_addr.port();
+                // Several applications in a host can have the same user, but not the same port
+                let known_endpoints = self.state.server_endpoints(server_addr);
+                if own_announcement {
+                    if self.pending_self_test.take().is_some() {
+                        let info = "Multicast OK: the own announcement arrived";
+                        self.state
+                            .add_system_message(info.into(), SystemMessageType::Info);
+                    }
+                } else if known_endpoints.is_empty() || !repeated {
+                    // Announced from startup again: it was restarted, the old connection is dead
+                    for known_endpoint in known_endpoints {
+                        self.close_peer(known_endpoint);
+                    }
+                    if self.is_peer_limit_reached() {
+                        return;
+                    }
+                    let user_endpoint = self.network.connect_tcp(server_addr).unwrap();
+                    let peer = Peer {
+                        user,
+                        server_port: Some(server_port),
+                        version,
+                    };
+                    self.state.connecting_user(user_endpoint, peer.clone());
+                    let hello = NetMessage::HelloUser(
+                        self.config.user_name.clone(),
+                        PROTOCOL_VERSION,
+                        Some(self.server_addr.port()),
+                    );
+                    if self.network.send(user_endpoint, hello).is_err() {
+                        return self.close_peer(user_endpoint);
+                    }
+                    if let Some(motd) = self.config.motd.clone() {
+                        let message = NetMessage::Motd(motd);
+                        if self.understands(user_endpoint, &message) {
+                            self.network.send(user_endpoint, message).ok();
                         }
-                        NetMessage::UserMessage(content) => {
-                            let user = state.user_name(endpoint).unwrap();
+                    }
+                    self.connect_user(user_endpoint, peer);
+                }
+            }
+            // by tcp:
+            NetMessage::HelloUser(user, version, server_port) => {
+                match util::sanitize_user_name(&user)
+                    .filter(|_| self.config.is_peer_allowed(endpoint.addr().ip()))
+                    .filter(|_| !self.is_peer_limit_reached())
+                {
+                    Some(user) => {
+                        // Messages that arrived before knowing who sent them
+                        let early_messages = self.state.take_early_messages(endpoint);
+                        let peer = Peer {
+                            user: user.clone(),
+                            server_port,
+                            version,
+                        };
+                        self.connect_user(endpoint, peer);
+                        for content in early_messages {
                             let message =
-                                LogMessage::new(user.into(), MessageType::Content(content));
-                            state.add_message(message);
+                                LogMessage::new(user.clone(), MessageType::Content(content));
+                            self.state.add_message(message);
                         }
-                    },
-                    NetEvent::AddedEndpoint(_) => (),
-                    NetEvent::RemovedEndpoint(endpoint) => {
-                        state.disconnected_user(endpoint);
-                    }
-                },
-                Event::Terminal(term_event) => match term_event {
-                    TermEvent::Key(KeyEvent { code, modifiers }) => match code {
-                        KeyCode::Esc => {
+                    }
+                    None => {
+                        self.state.count_rejected_peer();
+                        self.close_peer(endpoint);
+                    }
+                }
+            }
+            NetMessage::UserMessage(content) => {
+                if !self.is_within_message_rate(endpoint) {
+                    return;
+                }
+                let content = match self.limit_message_length(endpoint, content) {
+                    Some(content) => content,
+                    None => return,
+                };
+                match self.state.user_name(endpoint) {
+                    Some(user) => {
+                        let message = LogMessage::new(user.into(), MessageType::Content(content));
+                        self.state.add_message(message);
+                    }
+                    // Only a tcp connection can be waiting for its HelloUser
+                    None if Some(endpoint.resource_id()) != self.discovery_listener => {
+                        self.state.buffer_early_message(endpoint, content)
+                    }
+                    None => self.state.count_dropped_message(),
+                }
+            }
+            NetMessage::PrivateMessage(content) => {
+                if !self.is_within_message_rate(endpoint) {
+                    return;
+                }
+                let content = match self.limit_message_length(endpoint, content) {
+                    Some(content) => content,
+                    None => return,
+                };
+                match self.state.user_name(endpoint) {
+                    Some(user) => {
+                        let user = user.clone();
+                        self.state.whisper_received(&user, content);
+                    }
+                    // A whisper is only sent to known users, so it is not expected here
+                    None => self.state.count_dropped_message(),
+                }
+            }
+            NetMessage::Status(status) => {
+                if !self.is_within_message_rate(endpoint) {
+                    return;
+                }
+                if self.state.user_name(endpoint).is_some() {
+                    self.state
+                        .set_user_status(endpoint, util::sanitize_status(&status));
+                } else {
+                    self.state.count_dropped_message();
+                }
+            }
+            NetMessage::Motd(motd) => {
+                if !self.is_within_message_rate(endpoint) {
+                    return;
+                }
+                let user = match self.state.user_name(endpoint) {
+                    Some(user) => user.clone(),
+                    None => return self.state.count_dropped_message(),
+                };
+                if let Some(motd) = util::sanitize_motd(&motd) {
+                    if self.shown_motds.insert(motd.clone()) {
+                        let info = format!(
+                            "Message of the day from {}: {}",
+                            self.state.shown_name(&user),
+                            motd
+                        );
+                        self.state.add_system_message(info, SystemMessageType::Info);
+                    }
+                }
+            }
+        }
+    }
+
+    fn process_terminal_event(&mut self, term_event: TermEvent) {
+        match term_event {
+            TermEvent::Key(KeyEvent { code, modifiers }) => {
+                self.last_user_activity = Instant::now();
+                match code {
+                    KeyCode::Esc => {
+                        self.event_queue.sender().send_with_priority(Event::Close);
+                    }
+                    KeyCode::Char(character) => {
+                        if character == 'c' && modifiers.contains(KeyModifiers::CONTROL) {
                             self.event_queue.sender().send_with_priority(Event::Close);
+                        } else if character == 'z' && modifiers.contains(KeyModifiers::CONTROL) {
+                            self.state.input_undo();
+                        } else if character == 'y' && modifiers.contains(KeyModifiers::CONTROL) {
+                            self.state.input_redo();
+                        } else if character == 'w' && modifiers.contains(KeyModifiers::CONTROL) {
+                            self.state.input_remove_previous_word();
+                        } else if character == 'u' && modifiers.contains(KeyModifiers::CONTROL) {
+                            self.state.input_remove_to_start();
+                        } else if character == 'k' && modifiers.contains(KeyModifiers::CONTROL) {
+                            self.state.input_remove_to_end();
+                        } else {
+                            self.state.input_write(character);
                         }
-                        KeyCode::Char(character) => {
-                            if character == 'c' && modifiers.contains(KeyModifiers::CONTROL) {
-                                self.event_queue.sender().send_with_priority(Event::Close);
-                            } else {
-                                state.input_write(character);
-                            }
-                        }
-                        KeyCode::Enter => {
-                            if let Some(input) = state.reset_input() {
-                                let message = LogMessage::new(
-                                    format!("{} (me)", self.user_name),
-                                    MessageType::Content(input.clone()),
-                                );
-                                self.network
-                                    .send_all(
-                                        state.all_user_endpoints(),
-                                        NetMessage::UserMessage(input),
-                                    )
-                                    .unwrap();
-                                state.add_message(message);
-                            }
-                        }
-                        KeyCode::Delete => {
-                            state.input_remove();
-                        }
-                        KeyCode::Backspace => {
-                            state.input_remove_previous();
-                        }
-                        KeyCode::Left => {
-                            state.input_move_cursor(CursorMovement::Left);
-                        }
-                        KeyCode::Right => {
-                            state.input_move_cursor(CursorMovement::Right);
-                        }
-                        KeyCode::Home => {
-                            state.input_move_cursor(CursorMovement::Start);
-                        }
-                        KeyCode::End => {
-                            state.input_move_cursor(CursorMovement::End);
-                        }
-                        KeyCode::Up => {
-                            state.messages_scroll(ScrollMovement::Up);
-                        }
-                        KeyCode::Down => {
-                            state.messages_scroll(ScrollMovement::Down);
+                    }
+                    KeyCode::Enter => {
+                        if let Some(input) = self.state.reset_input() {
+                            self.submit(input);
                         }
-                        KeyCode::PageUp => {
-                            state.messages_scroll(ScrollMovement::Start);
+                    }
+                    KeyCode::Delete => {
+                        self.state.input_remove();
+                    }
+                    KeyCode::Backspace if modifiers.contains(KeyModifiers::ALT) => {
+                        self.state.input_remove_previous_word();
+                    }
+                    KeyCode::Backspace => {
+                        self.state.input_remove_previous();
+                    }
+                    KeyCode::Left => {
+                        self.state.input_move_cursor(CursorMovement::Left);
+                    }
+                    KeyCode::Right => {
+                        self.state.input_move_cursor(CursorMovement::Right);
+                    }
+                    KeyCode::Home => {
+                        self.state.input_move_cursor(CursorMovement::Start);
+                    }
+                    KeyCode::End => {
+                        self.state.input_move_cursor(CursorMovement::End);
+                    }
+                    KeyCode::Up if modifiers.contains(KeyModifiers::SHIFT) => {
+                        self.state.activity_scroll(ScrollMovement::Up);
+                    }
+                    KeyCode::Down if modifiers.contains(KeyModifiers::SHIFT) => {
+                        self.state.activity_scroll(ScrollMovement::Down);
+                    }
+                    KeyCode::Up => {
+                        self.state.messages_scroll(ScrollMovement::Up);
+                    }
+                    KeyCode::Down => {
+                        self.state.messages_scroll(ScrollMovement::Down);
+                    }
+                    KeyCode::PageUp => {
+                        self.state.messages_scroll(ScrollMovement::Start);
+                    }
+                    KeyCode::F(2) => {
+                        self.state.toggle_users_panel();
+                    }
+                    KeyCode::F(3) => {
+                        self.state.toggle_message_layout();
+                    }
+                    _ => (),
+                }
+            }
+            TermEvent::Mouse(_) => (),
+            TermEvent::Resize(_, _) => (),
+        }
+    }
+
+    fn check_idle(&mut self) {
+        let idle_timeout = self.config.idle_timeout.unwrap();
+        let idle_time = self.last_user_activity.elapsed();
+        if idle_time >= idle_timeout {
+            // There is no goodbye message in the protocol:
+            // the peers see the user leaving when the connection is closed.
+            let endpoints = self.state.all_peer_endpoints().copied().collect::<Vec<_>>();
+            for endpoint in endpoints {
+                self.network.remove_resource(endpoint.resource_id());
+            }
+            self.event_queue.sender().send_with_priority(Event::Close);
+        } else {
+            // Check again when the timeout would expire from the last activity
+            self.event_queue
+                .sender()
+                .send_with_timer(Event::IdleCheck, idle_timeout - idle_time);
+        }
+    }
+
+    fn connect_user(&mut self, endpoint: Endpoint, peer: Peer) {
+        let user = &peer.user.clone();
+        self.state.connected_user(endpoint, peer);
+        if let Some(status) = self.status.clone() {
+            let message = NetMessage::Status(status);
+            if Some(endpoint) == self.loopback_endpoint {
+                self.process_network_message(endpoint, message);
+            } else if self.understands(endpoint, &message) {
+                self.network.send(endpoint, message).ok();
+            }
+        }
+        if let Some(ttl) = self.config.offline_buffer_ttl {
+            let pending_messages = self.state.take_offline_messages(user, ttl);
+            if !pending_messages.is_empty() {
+                let info = format!(
+                    "Delivering {} messages sent while {} was offline",
+                    pending_messages.len(),
+                    self.state.shown_name(user)
+                );
+                self.state.add_system_message(info, SystemMessageType::Info);
+            }
+            self.deliver_offline_messages(endpoint, pending_messages);
+        }
+    }
+
+    /// Sends the messages in batches, so the rate limit of the receiver does not drop them.
+    fn deliver_offline_messages(&mut self, endpoint: Endpoint, mut messages: Vec<String>) {
+        if self.state.user_name(endpoint).is_none() {
+            return; // Disconnected again before receiving them
+        }
+        let remaining = messages.split_off(messages.len().min(OFFLINE_DELIVERY_BATCH));
+        for content in messages {
+            if self
+                .network
+                .send(endpoint, NetMessage::UserMessage(content))
+                .is_err()
+            {
+                return;
+            }
+        }
+        if !remaining.is_empty() {
+            self.event_queue.sender().send_with_timer(
+                Event::OfflineDelivery(endpoint, remaining),
+                Duration::from_millis(OFFLINE_DELIVERY_INTERVAL),
+            );
+        }
+    }
+
+    /// Processes the text written by the user, as a command or as a message to all users.
+    /// Commands are never sent to others, they are only echoed locally.
+    /// Text given here goes through the same path as the one submitted from the input.
+    pub fn submit(&mut self, input: String) {
+        let command = match Command::parse(&input, &self.config) {
+            Some(command) => command,
+            None => return self.send_message(input),
+        };
+        // A whisper is already shown as such
+        if !matches!(command, Ok(Command::Whisper(..)) | Ok(Command::Reply(_))) {
+            let user = self.config.display_name.clone();
+            self.state
+                .add_message(LogMessage::new(user, MessageType::Command(input)));
+        }
+        match command {
+            Ok(command) => self.process_command(command),
+            Err(error) => self
+                .state
+                .add_system_message(error, SystemMessageType::Error),
+        }
+    }
+
+    /// Sends the content to all users, showing it also in the own chat.
+    fn send_message(&mut self, content: String) {
+        let content = match &self.config.signature {
+            Some(signature) => format!("{} -- {}", content, signature),
+            None => content,
+        };
+        let message = LogMessage::new(
+            format!("{} (me)", self.config.display_name),
+            MessageType::ContentSent(content.clone()),
+        );
+        self.state.add_message(message);
+        self.broadcast(NetMessage::UserMessage(content.clone()));
+        // After broadcasting, so the users pruned by it also get the message when reconnecting
+        if let Some(ttl) = self.config.offline_buffer_ttl {
+            self.state.store_offline_message(&content, ttl);
+        }
+        if let Some(endpoint) = self.loopback_endpoint {
+            self.process_network_message(endpoint, NetMessage::UserMessage(content));
+        }
+        if self.state.all_user_endpoints().next().is_none() {
+            let warning = "No users connected, nobody received the message";
+            self.state
+                .add_system_message(warning.into(), SystemMessageType::Warning);
+        }
+    }
+
+    /// Checks if the peer is of a version that can decode the message.
+    fn understands(&self, endpoint: Endpoint, message: &NetMessage) -> bool {
+        self.state.peer_version(endpoint) >= message.version()
+    }
+
+    /// Number of users that would not receive the message in a broadcast because of their version.
+    fn older_users(&self, message: &NetMessage) -> usize {
+        self.state
+            .all_user_endpoints()
+            .filter(|endpoint| !self.understands(**endpoint, message))
+            .count()
+    }
+
+    /// Sends the message to all users that understand it, except the loopback one.
+    /// An endpoint can be already closed before its removal event is processed,
+    /// so the users that can not be reached are removed instead of failing the whole sending.
+    fn broadcast(&mut self, message: NetMessage) {
+        let loopback_endpoint = self.loopback_endpoint;
+        let endpoints = self
+            .state
+            .all_user_endpoints()
+            .copied()
+            .filter(|endpoint| Some(*endpoint) != loopback_endpoint)
+            .filter(|endpoint| self.understands(*endpoint, &message))
+            .collect::<Vec<_>>();
+        let result = self.network.send_all(endpoints.iter(), message);
+        if let Err(failures) = result {
+            for (endpoint, _) in &failures {
+                self.network.remove_resource(endpoint.resource_id());
+            }
+            let endpoints = failures
+                .iter()
+                .map(|(endpoint, _)| *endpoint)
+                .collect::<Vec<_>>();
+            let offline_buffer = self.config.offline_buffer_ttl.is_some();
+            self.state
+                .remove_unreachable_peers(failures, offline_buffer);
+            for endpoint in endpoints {
+                self.event_queue.sender().send(Event::PeerClosed(endpoint));
+            }
+        }
+    }
+
+    /// Applies the configured message rate limit to the endpoint.
+    /// Returns `false` if its message must be discarded.
+    fn is_within_message_rate(&mut self, endpoint: Endpoint) -> bool {
+        let limit = match &self.config.message_rate_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        match self.state.limit_message_rate(endpoint, limit) {
+            MessageRate::Accepted => true,
+            MessageRate::Dropped | MessageRate::Ignored => false,
+            MessageRate::Flooding => {
+                let warning = format!(
+                    "{} is flooding the chat, its messages are ignored",
+                    self.peer_name(endpoint)
+                );
+                self.state
+                    .add_system_message(warning, SystemMessageType::Warning);
+                false
+            }
+        }
+    }
+
+    /// Applies the configured maximum length to a received content.
+    /// Returns `None` if the content must be discarded.
+    fn limit_message_length(&mut self, endpoint: Endpoint, content: String) -> Option<String> {
+        let max_length = self.config.max_message_length;
+        match self.config.long_message_policy.apply(content, max_length) {
+            Some(content) => Some(content),
+            None => {
+                self.state.count_dropped_message();
+                let warning = format!(
+                    "A message from {} was too long and was dropped",
+                    self.peer_name(endpoint)
+                );
+                self.state
+                    .add_system_message(warning, SystemMessageType::Warning);
+                None
+            }
+        }
+    }
+
+    /// The user of the endpoint, or its address if the user is still unknown.
+    fn peer_name(&self, endpoint: Endpoint) -> String {
+        match self.state.user_name(endpoint) {
+            Some(user) => self.state.shown_name(user).into(),
+            None => endpoint.addr().to_string(),
+        }
+    }
+
+    /// Sends the content only to the endpoints of the user.
+    fn whisper(&mut self, user: String, content: String) {
+        let user = self.state.user_by_name(&user).to_string();
+        let endpoints = self
+            .state
+            .user_endpoints(&user)
+            .copied()
+            .collect::<Vec<_>>();
+        if endpoints.is_empty() {
+            let error = format!("{} is not connected", self.state.shown_name(&user));
+            self.state
+                .add_system_message(error, SystemMessageType::Error);
+            return;
+        }
+        let loopback_endpoint = self.loopback_endpoint;
+        let message = NetMessage::PrivateMessage(content.clone());
+        if !endpoints
+            .iter()
+            .all(|endpoint| self.understands(*endpoint, &message))
+        {
+            let error = format!(
+                "{} uses an older version of termchat, without whispers",
+                self.state.shown_name(&user)
+            );
+            self.state
+                .add_system_message(error, SystemMessageType::Error);
+            return;
+        }
+        for endpoint in endpoints
+            .iter()
+            .filter(|endpoint| Some(**endpoint) != loopback_endpoint)
+        {
+            let message = NetMessage::PrivateMessage(content.clone());
+            if let Err(err) = self.network.send(*endpoint, message) {
+                let error = format!(
+                    "Could not whisper to {}: {}",
+                    self.state.shown_name(&user),
+                    err
+                );
+                self.state
+                    .add_system_message(error, SystemMessageType::Error);
+                return;
+            }
+        }
+        let message = LogMessage::new(user, MessageType::WhisperSent(content.clone()));
+        self.state.add_message(message);
+        if let Some(endpoint) = loopback_endpoint.filter(|endpoint| endpoints.contains(endpoint)) {
+            self.process_network_message(endpoint, NetMessage::PrivateMessage(content));
+        }
+    }
+
+    /// Changes the own status, sending it to all users. `None` clears it.
+    fn set_status(&mut self, status: Option<String>) {
+        let status = match status.map(|status| util::sanitize_status(&status)) {
+            Some(None) => {
+                let error = "The status must contain at least one printable character";
+                self.state
+                    .add_system_message(error.into(), SystemMessageType::Error);
+                return;
+            }
+            Some(Some(status)) => Some(status),
+            None => None,
+        };
+        self.status = status.clone();
+        let status = status.unwrap_or_default();
+        let message = NetMessage::Status(status.clone());
+        let older_users = self.older_users(&message);
+        self.broadcast(message);
+        if let Some(endpoint) = self.loopback_endpoint {
+            self.process_network_message(endpoint, NetMessage::Status(status.clone()));
+        }
+        let mut info = if status.is_empty() {
+            "Status cleared".into()
+        } else {
+            format!("Status set to '{}'", status)
+        };
+        if older_users > 0 {
+            info.push_str(&format!(
+                ", not shown to {} users of older versions",
+                older_users
+            ));
+        }
+        self.state.add_system_message(info, SystemMessageType::Info);
+    }
+
+    /// Shows the user with another name only in the own chat. `None` restores its name.
+    fn set_alias(&mut self, user: String, alias: Option<String>) {
+        let user = match util::sanitize_user_name(&user) {
+            Some(user) => self.state.user_by_name(&user).to_string(),
+            None => {
+                let error = "The user name must contain at least one printable character";
+                self.state
+                    .add_system_message(error.into(), SystemMessageType::Error);
+                return;
+            }
+        };
+        let info = match alias.map(|alias| util::sanitize_user_name(&alias)) {
+            Some(None) => {
+                let error = "The local name must contain at least one printable character";
+                self.state
+                    .add_system_message(error.into(), SystemMessageType::Error);
+                return;
+            }
+            Some(Some(alias)) if !self.state.is_alias_available(&user, &alias) => {
+                let error = format!("{} is already the name of another user", alias);
+                self.state
+                    .add_system_message(error, SystemMessageType::Error);
+                return;
+            }
+            Some(Some(alias)) => {
+                let info = format!("{} is shown as {}", user, alias);
+                self.state.set_alias(user, Some(alias));
+                info
+            }
+            None => {
+                let info = format!("{} is shown with its own name again", user);
+                self.state.set_alias(user, None);
+                info
+            }
+        };
+        self.state.add_system_message(info, SystemMessageType::Info);
+    }
+
+    fn process_command(&mut self, command: Command) {
+        match command {
+            Command::Version => {
+                let info = format!("termchat {}", env!("CARGO_PKG_VERSION"));
+                self.state.add_system_message(info, SystemMessageType::Info);
+            }
+            Command::Stats => {
+                let drop_counters = self.state.drop_counters();
+                let info = format!(
+                    "Dropped messages: {}, rejected peers: {}",
+                    drop_counters.messages, drop_counters.peers
+                );
+                self.state.add_system_message(info, SystemMessageType::Info);
+            }
+            Command::RosterExport(path) => {
+                let thresholds = &self.config.presence_thresholds;
+                let mut users = self
+                    .state
+                    .all_users_activity()
+                    .map(|(endpoint, user, silence)| RosterEntry {
+                        user,
+                        addr: endpoint.addr(),
+                        presence: thresholds.presence(silence),
+                        silence,
+                    })
+                    .collect::<Vec<_>>();
+                users.sort_by_key(|entry| entry.user);
+                match export::write_roster(&path, &users) {
+                    Ok(()) => {
+                        let info = format!("Exported {} users to {}", users.len(), path.display());
+                        self.state.add_system_message(info, SystemMessageType::Info);
+                    }
+                    Err(err) => {
+                        let error = format!("Could not export to {}: {}", path.display(), err);
+                        self.state
+                            .add_system_message(error, SystemMessageType::Error);
+                    }
+                }
+            }
+            Command::Group(discovery_addr) => self.change_discovery_group(discovery_addr),
+            Command::Status(status) => self.set_status(status),
+            Command::Alias(user, alias) => self.set_alias(user, alias),
+            Command::Debug => {
+                self.state.toggle_debug();
+                let info = if self.state.debug() {
+                    "Debug mode enabled, network events are shown"
+                } else {
+                    "Debug mode disabled"
+                };
+                self.state
+                    .add_system_message(info.into(), SystemMessageType::Info);
+            }
+            Command::Whisper(user, content) => self.whisper(user, content),
+            Command::Reply(content) => match self.state.last_whisperer() {
+                Some(user) => self.whisper(user.clone(), content),
+                None => {
+                    let error = "Nobody has whispered to you yet";
+                    self.state
+                        .add_system_message(error.into(), SystemMessageType::Error);
+                }
+            },
+            Command::Save(path, count) => {
+                let messages = self.state.messages();
+                let first = count.map_or(0, |count| messages.len().saturating_sub(count));
+                let messages = &messages[first..];
+                match export::write_transcript(&path, messages, self.config.save_whispers) {
+                    Ok(saved) => {
+                        let info = format!("Saved {} messages to {}", saved, path.display());
+                        self.state.add_system_message(info, SystemMessageType::Info);
+                    }
+                    Err(err) => {
+                        let error = format!("Could not save to {}: {}", path.display(), err);
+                        self.state
+                            .add_system_message(error, SystemMessageType::Error);
+                    }
+                }
+            }
+            Command::SelfTest => {
+                let local_server_addr = SocketAddr::from(([127, 0, 0, 1], self.server_addr.port()));
+                match TcpStream::connect_timeout(&local_server_addr, Duration::from_secs(1)) {
+                    Ok(_) => {
+                        let info = "TCP OK: the listener accepts connections";
+                        self.state
+                            .add_system_message(info.into(), SystemMessageType::Info);
+                    }
+                    Err(err) => {
+                        let error = format!("TCP listener does not accept connections: {}", err);
+                        self.state
+                            .add_system_message(error, SystemMessageType::Error);
+                    }
+                }
+
+                // Marked as repeated, so the users already connected do not connect again
+                let message = self.hello_lan(true);
+                let sent = match self.discovery_endpoint {
+                    Some(endpoint) => self.network.send(endpoint, message).is_ok(),
+                    None => false,
+                };
+                if sent {
+                    let start = Instant::now();
+                    self.pending_self_test = Some(start);
+                    self.event_queue.sender().send_with_timer(
+                        Event::SelfTestTimeout(start),
+                        Duration::from_secs(SELF_TEST_TIMEOUT),
+                    );
+                } else {
+                    let error = "No multicast: the discovery message could not be sent";
+                    self.state
+                        .add_system_message(error.into(), SystemMessageType::Error);
+                }
+            }
+            Command::NetInfo => {
+                let discovery_addr = self.config.discovery_addr;
+                let info = format!("Listening TCP connections at {}", self.server_addr);
+                self.state.add_system_message(info, SystemMessageType::Info);
+                let info = format!(
+                    "Discovering users through multicast group {}",
+                    discovery_addr
+                );
+                self.state.add_system_message(info, SystemMessageType::Info);
+                match util::local_interfaces() {
+                    Ok(interfaces) => {
+                        for interface in interfaces {
+                            let info = format!(
+                                "Interface {}: {}{}{}",
+                                interface.name,
+                                interface.ip,
+                                if interface.up { "" } else { ", down" },
+                                if interface.multicast {
+                                    ""
+                                } else {
+                                    ", no multicast"
+                                },
+                            );
+                            self.state.add_system_message(info, SystemMessageType::Info);
                         }
-                        _ => (),
-                    },
-                    TermEvent::Mouse(_) => (),
-                    TermEvent::Resize(_, _) => (),
-                },
-                Event::Close => break,
+                    }
+                    Err(err) => {
+                        let warning = format!("The local interfaces could not be listed: {}", err);
+                        self.state
+                            .add_system_message(warning, SystemMessageType::Warning);
+                    }
+                }
+                match util::local_ip_towards(discovery_addr) {
+                    Ok(ip) => {
+                        let info = format!("Multicast messages are sent from the interface {}", ip);
+                        self.state.add_system_message(info, SystemMessageType::Info);
+                    }
+                    Err(_) => {
+                        let warning = format!("No interface has a route to {}", discovery_addr);
+                        self.state
+                            .add_system_message(warning, SystemMessageType::Warning);
+                    }
+                }
             }
-            ui::draw(&mut self.terminal, &state);
         }
     }
 }
@@ -196,3 +1258,167 @@ impl Drop for Application {
         terminal::disable_raw_mode().unwrap()
     }
 }
+
+/// Shows the result of starting the discovery at the attempt.
+/// Returns the time to wait before retrying it, `None` if it started or must be given up.
+fn report_discovery(
+    state: &mut ApplicationState,
+    attempt: usize,
+    result: &io::Result<()>,
+) -> Option<Duration> {
+    match result {
+        Ok(()) => {
+            if attempt > 0 {
+                let info = "Discovery started, users in the LAN can be found now";
+                state.add_system_message(info.into(), SystemMessageType::Info);
+            }
+            None
+        }
+        Err(err) => {
+            if attempt == 0 {
+                let warning = format!(
+                    "Discovery failed, users in the LAN may not be found automatically: {}. \
+                    Check that the firewall allows multicast and the network interface is up, \
+                    retrying in the background",
+                    err
+                );
+                state.add_system_message(warning, SystemMessageType::Warning);
+            }
+            if attempt < MAX_DISCOVERY_RETRIES {
+                Some(Duration::from_secs(DISCOVERY_RETRY_INTERVAL))
+            } else {
+                let error = format!("Discovery given up after {} retries: {}", attempt, err);
+                state.add_system_message(error, SystemMessageType::Error);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system_messages(state: &ApplicationState) -> Vec<&str> {
+        state
+            .messages()
+            .iter()
+            .filter_map(|message| match &message.message_type {
+                MessageType::System(content, _) => Some(content.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn discovery_retried_a_bounded_number_of_times() {
+        let mut state = ApplicationState::new();
+        let failure = || {
+            Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no multicast",
+            ))
+        };
+
+        let retry = Some(Duration::from_secs(DISCOVERY_RETRY_INTERVAL));
+        assert_eq!(report_discovery(&mut state, 0, &failure()), retry);
+        let messages = system_messages(&state);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("Discovery failed"));
+        assert!(messages[0].contains("no multicast"));
+        assert!(matches!(
+            state.messages()[0].message_type,
+            MessageType::System(_, SystemMessageType::Warning)
+        ));
+
+        // The failed retries are not reported again until giving up
+        for attempt in 1..MAX_DISCOVERY_RETRIES {
+            assert_eq!(report_discovery(&mut state, attempt, &failure()), retry);
+        }
+        assert_eq!(system_messages(&state).len(), 1);
+
+        assert_eq!(
+            report_discovery(&mut state, MAX_DISCOVERY_RETRIES, &failure()),
+            None
+        );
+        let messages = system_messages(&state);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[1],
+            format!(
+                "Discovery given up after {} retries: no multicast",
+                MAX_DISCOVERY_RETRIES
+            )
+        );
+        assert!(matches!(
+            state.messages()[1].message_type,
+            MessageType::System(_, SystemMessageType::Error)
+        ));
+    }
+
+    #[test]
+    fn discovery_started_after_retrying() {
+        let mut state = ApplicationState::new();
+        assert_eq!(report_discovery(&mut state, 0, &Ok(())), None);
+        assert!(system_messages(&state).is_empty());
+
+        assert_eq!(report_discovery(&mut state, 2, &Ok(())), None);
+        assert_eq!(
+            system_messages(&state),
+            vec!["Discovery started, users in the LAN can be found now"]
+        );
+    }
+
+    /// The messages of the applications without a protocol version.
+    #[derive(Serialize, Deserialize)]
+    enum NetMessageV0 {
+        HelloLan(String, u16),
+        HelloUser(String),
+        UserMessage(String),
+    }
+
+    #[test]
+    fn messages_from_an_older_version() {
+        let data = bincode::serialize(&NetMessageV0::HelloLan("bob".into(), 3001)).unwrap();
+        assert!(matches!(
+            bincode::deserialize(&data).unwrap(),
+            NetMessage::HelloLan(user, 3001, 0, false) if user == "bob"
+        ));
+        let data = bincode::serialize(&NetMessageV0::HelloUser("bob".into())).unwrap();
+        assert!(matches!(
+            bincode::deserialize(&data).unwrap(),
+            NetMessage::HelloUser(user, 0, None) if user == "bob"
+        ));
+        let data = bincode::serialize(&NetMessageV0::UserMessage("hi".into())).unwrap();
+        assert!(matches!(
+            bincode::deserialize(&data).unwrap(),
+            NetMessage::UserMessage(content) if content == "hi"
+        ));
+    }
+
+    #[test]
+    fn messages_to_an_older_version() {
+        let hello = NetMessage::HelloLan("alice".into(), 3000, PROTOCOL_VERSION, true);
+        let data = bincode::serialize(&hello).unwrap();
+        assert!(matches!(
+            bincode::deserialize(&data).unwrap(),
+            NetMessageV0::HelloLan(user, 3000) if user == "alice"
+        ));
+        let hello = NetMessage::HelloUser("alice".into(), PROTOCOL_VERSION, Some(3000));
+        let data = bincode::serialize(&hello).unwrap();
+        assert!(matches!(
+            bincode::deserialize(&data).unwrap(),
+            NetMessageV0::HelloUser(user) if user == "alice"
+        ));
+
+        // The newer ones are only sent to the peers that tell their version
+        assert_eq!(NetMessage::UserMessage("hi".into()).version(), 0);
+        for message in &[
+            NetMessage::PrivateMessage("hi".into()),
+            NetMessage::Status("away".into()),
+            NetMessage::Motd("welcome".into()),
+        ] {
+            assert!(message.version() > 0 && message.version() <= PROTOCOL_VERSION);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use super::config::Config;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Name and parameters of every command.
+const COMMAND_SIGNATURES: [(&str, &str); 12] = [
+    ("version", ""),
+    ("stats", ""),
+    ("netinfo", ""),
+    ("selftest", ""),
+    ("debug", ""),
+    ("group", "<multicast ip:port>"),
+    ("roster", "export <file>"),
+    ("status", "set <text> | clear"),
+    ("alias", "<user> [<local name>]"),
+    ("save", "<file> [<number of last messages>]"),
+    ("w", "<user> <text>"),
+    ("r", "<text>"),
+];
+
+pub enum Command {
+    Version,
+    Stats,
+    NetInfo,
+    SelfTest,
+    Debug,
+    Group(SocketAddr),             // discovery multicast address
+    RosterExport(PathBuf),         // file
+    Status(Option<String>),        // text, None to clear it
+    Alias(String, Option<String>), // user, local name (None to remove it)
+    Save(PathBuf, Option<usize>),  // file, number of last messages (all if None)
+    Whisper(String, String),       // user, content
+    Reply(String),                 // content, to the last user that whispered
+}
+
+/// What the user input is going to be once submitted.
+pub enum InputKind {
+    Text,
+    Command(String), // usage
+    UnknownCommand,
+}
+
+impl Command {
+    /// Parses the user input as a command.
+    /// Returns `None` if the input is not intended to be a command,
+    /// or an error describing the usage if the command is not well formed.
+    pub fn parse(input: &str, config: &Config) -> Option<Result<Command, String>> {
+        let (name, params) = split_command(input, config)?;
+        let prefix = config.command_prefix;
+
+        let command = match (name, params.as_slice()) {
+            ("version", []) => Command::Version,
+            ("stats", []) => Command::Stats,
+            ("netinfo", []) => Command::NetInfo,
+            ("selftest", []) => Command::SelfTest,
+            ("debug", []) => Command::Debug,
+            ("roster", ["export", file]) => Command::RosterExport(file.into()),
+            ("status", ["set", text @ ..]) if !text.is_empty() => {
+                Command::Status(Some(text.join(" ")))
+            }
+            ("status", ["clear"]) => Command::Status(None),
+            ("alias", [user]) => Command::Alias(user.to_string(), None),
+            ("alias", [user, alias]) => Command::Alias(user.to_string(), Some(alias.to_string())),
+            ("group", [addr]) => match addr.parse::<SocketAddr>() {
+                Ok(addr) if addr.is_ipv4() && addr.ip().is_multicast() => Command::Group(addr),
+                _ => return Some(Err(usage_error(name, prefix))),
+            },
+            ("save", [file]) => Command::Save(file.into(), None),
+            ("save", [file, count]) => match count.parse() {
+                Ok(count) => Command::Save(file.into(), Some(count)),
+                Err(_) => return Some(Err(usage_error(name, prefix))),
+            },
+            ("w", [user, text @ ..]) if !text.is_empty() => {
+                Command::Whisper(user.to_string(), text.join(" "))
+            }
+            ("r", text) if !text.is_empty() => Command::Reply(text.join(" ")),
+            _ => return Some(Err(usage_error(name, prefix))),
+        };
+        Some(Ok(command))
+    }
+}
+
+/// Classifies the user input while it is being written.
+pub fn input_kind(input: &str, config: &Config) -> InputKind {
+    match split_command(input, config) {
+        None => InputKind::Text,
+        Some((name, _)) => match usage(name, config.command_prefix) {
+            Some(usage) => InputKind::Command(usage),
+            None => InputKind::UnknownCommand,
+        },
+    }
+}
+
+/// Splits the input into the command name and its parameters.
+/// Returns `None` if the input is not intended to be a command.
+fn split_command<'a>(input: &'a str, config: &Config) -> Option<(&'a str, Vec<&'a str>)> {
+    if !input.starts_with(config.command_prefix) {
+        return None;
+    }
+    let input = &input[config.command_prefix.len_utf8()..];
+    if input.is_empty() || input.starts_with(char::is_whitespace) {
+        return None; // The prefix alone is just text
+    }
+
+    let mut words = input.split_whitespace();
+    let name = words.next().unwrap();
+    if config.unknown_commands_as_text && usage(name, config.command_prefix).is_none() {
+        return None;
+    }
+    Some((name, words.collect()))
+}
+
+fn usage_error(name: &str, prefix: char) -> String {
+    match usage(name, prefix) {
+        Some(usage) => format!("Usage: {}", usage),
+        None => format!("Unknown command '{}{}'", prefix, name),
+    }
+}
+
+fn usage(name: &str, prefix: char) -> Option<String> {
+    COMMAND_SIGNATURES
+        .iter()
+        .find(|(command_name, _)| *command_name == name)
+        .map(|(name, params)| format!("{}{} {}", prefix, name, params).trim_end().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(unknown_commands_as_text: bool) -> Config {
+        let mut config = Config::test();
+        config.unknown_commands_as_text = unknown_commands_as_text;
+        config
+    }
+
+    #[test]
+    fn unknown_command_is_not_sent() {
+        let config = config(false);
+        assert!(matches!(
+            Command::parse("?send hello", &config),
+            Some(Err(error)) if error == "Unknown command '?send'"
+        ));
+        assert!(matches!(
+            input_kind("?send hello", &config),
+            InputKind::UnknownCommand
+        ));
+    }
+
+    #[test]
+    fn unknown_command_as_text() {
+        let config = config(true);
+        assert!(Command::parse("?send hello", &config).is_none());
+        assert!(matches!(
+            input_kind("?send hello", &config),
+            InputKind::Text
+        ));
+        // The known ones are still commands
+        assert!(matches!(
+            Command::parse("?stats", &config),
+            Some(Ok(Command::Stats))
+        ));
+    }
+
+    #[test]
+    fn prefix_without_command_is_text() {
+        let config = config(false);
+        for input in &["hello", "?", "? hello", ""] {
+            assert!(Command::parse(input, &config).is_none());
+            assert!(matches!(input_kind(input, &config), InputKind::Text));
+        }
+    }
+
+    #[test]
+    fn command_with_wrong_parameters() {
+        let config = config(false);
+        assert!(matches!(
+            Command::parse("?w bob  hi   there", &config),
+            Some(Ok(Command::Whisper(user, content))) if user == "bob" && content == "hi there"
+        ));
+        assert!(matches!(
+            Command::parse("?w bob", &config),
+            Some(Err(error)) if error == "Usage: ?w <user> <text>"
+        ));
+        assert!(matches!(
+            input_kind("?w bob", &config),
+            InputKind::Command(usage) if usage == "?w <user> <text>"
+        ));
+    }
+}
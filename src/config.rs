@@ -0,0 +1,199 @@
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub struct MessageRateLimit {
+    pub rate: f64, // messages per second
+    pub burst: u32,
+}
+
+/// Silence of a peer before showing it as idle or stale.
+pub struct PresenceThresholds {
+    pub idle: Duration,
+    pub stale: Duration,
+}
+
+pub enum Presence {
+    Active,
+    Idle,
+    Stale,
+}
+
+impl PresenceThresholds {
+    pub fn presence(&self, silence: Duration) -> Presence {
+        if silence >= self.stale {
+            Presence::Stale
+        } else if silence >= self.idle {
+            Presence::Idle
+        } else {
+            Presence::Active
+        }
+    }
+}
+
+/// Limits a log by moving it aside when it is too big or from a previous day.
+#[derive(Clone, Copy)]
+pub struct LogRotation {
+    pub max_size: Option<u64>, // bytes, None means no limit of size
+    pub daily: bool,           // Also moved when the day changes
+    pub kept_files: usize,     // Previous files kept, the older ones are deleted
+}
+
+/// What to do with a received message longer than the maximum.
+pub enum LongMessagePolicy {
+    Truncate,
+    Drop,
+}
+
+impl LongMessagePolicy {
+    /// Limits the content to `max_length` characters.
+    /// Returns `None` if it is longer and must be dropped.
+    pub fn apply(&self, content: String, max_length: usize) -> Option<String> {
+        if content.len() <= max_length || content.chars().count() <= max_length {
+            return Some(content);
+        }
+        match self {
+            LongMessagePolicy::Truncate => {
+                let mut content = content.chars().take(max_length).collect::<String>();
+                content.push_str(" (truncated)");
+                Some(content)
+            }
+            LongMessagePolicy::Drop => None,
+        }
+    }
+}
+
+/// Glyphs shown before each message, telling its direction or kind.
+pub enum MessageIcons {
+    Unicode,
+    Ascii, // For terminals or fonts lacking the unicode ones
+}
+
+pub enum ActivityPanelSide {
+    Right,
+    Below,
+}
+
+/// Shows the system messages in their own panel, apart from the chat.
+pub struct ActivityPanel {
+    pub side: ActivityPanelSide,
+    pub chat_percentage: u16, // Space for the chat, the rest is for the activity
+}
+
+pub struct Config {
+    pub discovery_addr: SocketAddr,
+    pub tcp_ports: RangeInclusive<u16>, // The first free port is used, 0 means any free port
+    pub user_name: String,              // Identifies the user in the network
+    pub display_name: String,           // Only used to show the own user locally
+    pub signature: Option<String>,      // Appended to every message sent to all users
+    pub status: Option<String>,         // Shared with the other users, set with the status command
+    pub motd: Option<String>,           // Sent to the users that join, None to send nothing
+    pub input_prompt: String,           // Shown before the input text
+    pub command_prefix: char,
+    pub unknown_commands_as_text: bool, // Send it as a message instead of reporting an error
+    pub idle_timeout: Option<Duration>, // None means never close by inactivity
+    pub allowed_peers: Option<Vec<IpAddr>>, // None means any peer is allowed
+    pub aliases: Vec<(String, String)>, // user, local name shown instead
+    pub max_peers: Option<usize>,       // None means no limit of connected users
+    pub show_users_panel: bool,
+    pub loopback: bool, // Your own messages are also received from a synthetic user
+    pub presence_thresholds: PresenceThresholds,
+    pub activity_panel: Option<ActivityPanel>, // None means system messages are shown in the chat
+    pub compact_layout: bool,
+    pub message_icons: Option<MessageIcons>, // None means no icons
+    pub max_message_length: usize,           // characters of a received message
+    pub long_message_policy: LongMessagePolicy,
+    pub message_rate_limit: Option<MessageRateLimit>, // None means no limit per peer
+    pub json_log: Option<PathBuf>, // Every message is also appended there as a JSON line
+    pub json_log_rotation: Option<LogRotation>, // None means the JSON log grows without limit
+    pub save_whispers: bool,       // Include the whispers in the saved transcripts and the JSON log
+    pub offline_buffer_ttl: Option<Duration>, // None means no messages are kept for offline users
+}
+
+impl Config {
+    pub fn is_peer_allowed(&self, ip: IpAddr) -> bool {
+        match &self.allowed_peers {
+            Some(allowed_peers) => allowed_peers.contains(&ip),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Configuration with the default values of the command line.
+    pub fn test() -> Config {
+        Config {
+            discovery_addr: "238.255.0.1:5877".parse().unwrap(),
+            tcp_ports: 0..=0,
+            user_name: "alice".into(),
+            display_name: "alice".into(),
+            signature: None,
+            status: None,
+            motd: None,
+            input_prompt: "> ".into(),
+            command_prefix: '?',
+            unknown_commands_as_text: false,
+            idle_timeout: None,
+            allowed_peers: None,
+            aliases: Vec::new(),
+            max_peers: None,
+            show_users_panel: false,
+            loopback: false,
+            presence_thresholds: PresenceThresholds {
+                idle: Duration::from_secs(60),
+                stale: Duration::from_secs(300),
+            },
+            activity_panel: None,
+            compact_layout: false,
+            message_icons: None,
+            max_message_length: 4096,
+            long_message_policy: LongMessagePolicy::Truncate,
+            message_rate_limit: None,
+            json_log: None,
+            json_log_rotation: None,
+            save_whispers: false,
+            offline_buffer_ttl: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_within_the_maximum() {
+        let content = "á".repeat(10); // 20 bytes, but 10 characters
+        for policy in &[LongMessagePolicy::Truncate, LongMessagePolicy::Drop] {
+            assert_eq!(policy.apply(content.clone(), 10), Some(content.clone()));
+        }
+    }
+
+    #[test]
+    fn truncate_oversized_message() {
+        let content = "a".repeat(5000);
+        let expected = format!("{} (truncated)", "a".repeat(4096));
+        assert_eq!(
+            LongMessagePolicy::Truncate.apply(content, 4096),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn truncate_at_a_character_boundary() {
+        // A byte cut at 5 would split the 'ñ' and the emoji
+        let content = "añ🎉ñ🎉ñ".to_string();
+        assert_eq!(
+            LongMessagePolicy::Truncate.apply(content, 3),
+            Some("añ🎉 (truncated)".into())
+        );
+    }
+
+    #[test]
+    fn drop_oversized_message() {
+        let content = "🎉".repeat(11);
+        assert_eq!(LongMessagePolicy::Drop.apply(content, 10), None);
+    }
+}
@@ -0,0 +1,364 @@
+use super::config::{Config, LogRotation, Presence};
+use super::state::{LogMessage, MessageType};
+
+use chrono::{DateTime, Local, NaiveDate};
+
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Writes the messages as a transcript in plain text,
+/// or in markdown if the file has the `md` extension.
+/// Returns the number of messages written, whispers are skipped unless included.
+pub fn write_transcript(
+    path: &Path,
+    messages: &[LogMessage],
+    include_whispers: bool,
+) -> io::Result<usize> {
+    let markdown = path.extension() == Some(OsStr::new("md"));
+    let mut file = BufWriter::new(File::create(path)?);
+    if markdown {
+        writeln!(file, "# termchat transcript\n")?;
+    }
+    let mut written = 0;
+    for message in messages {
+        let date = message.date.format("%Y-%m-%d %H:%M:%S");
+        let text = match &message.message_type {
+            MessageType::Connection => format!("{} is online", message.user),
+            MessageType::Disconnection => format!("{} is offline", message.user),
+            MessageType::Content(content) | MessageType::ContentSent(content) => {
+                format!("{}: {}", message.user, content)
+            }
+            MessageType::WhisperReceived(_) | MessageType::WhisperSent(_) if !include_whispers => {
+                continue
+            }
+            MessageType::WhisperReceived(content) => {
+                format!("{} (whisper): {}", message.user, content)
+            }
+            MessageType::WhisperSent(content) => {
+                format!("me (whisper to {}): {}", message.user, content)
+            }
+            MessageType::Command(input) => format!("{} > {}", message.user, input),
+            MessageType::System(content, _) => format!("{}: {}", message.user, content),
+        };
+        if markdown {
+            writeln!(file, "- `{}` {}", date, text)?;
+        } else {
+            writeln!(file, "{} {}", date, text)?;
+        }
+        written += 1;
+    }
+    file.flush()?;
+    Ok(written)
+}
+
+pub struct RosterEntry<'a> {
+    pub user: &'a str,
+    pub addr: SocketAddr,
+    pub presence: Presence,
+    pub silence: Duration, // Since something was received from the user
+}
+
+/// Writes the users as CSV, or as JSON if the file has the `json` extension.
+pub fn write_roster(path: &Path, users: &[RosterEntry]) -> io::Result<()> {
+    let json = path.extension() == Some(OsStr::new("json"));
+    let mut file = BufWriter::new(File::create(path)?);
+    let date = Local::now().to_rfc3339();
+    if json {
+        writeln!(file, "{{\"date\":{},\"users\":[", json_string(&date))?;
+    } else {
+        writeln!(file, "# termchat roster {}", date)?;
+        writeln!(file, "user,address,presence,silent_seconds")?;
+    }
+    for (index, entry) in users.iter().enumerate() {
+        let presence = match entry.presence {
+            Presence::Active => "active",
+            Presence::Idle => "idle",
+            Presence::Stale => "stale",
+        };
+        if json {
+            let separator = if index + 1 < users.len() { "," } else { "" };
+            writeln!(
+                file,
+                "{{\"user\":{},\"address\":\"{}\",\"presence\":\"{}\",\"silent_seconds\":{}}}{}",
+                json_string(entry.user),
+                entry.addr,
+                presence,
+                entry.silence.as_secs(),
+                separator,
+            )?;
+        } else {
+            writeln!(
+                file,
+                "{},{},{},{}",
+                csv_field(entry.user),
+                entry.addr,
+                presence,
+                entry.silence.as_secs(),
+            )?;
+        }
+    }
+    if json {
+        writeln!(file, "]}}")?;
+    }
+    file.flush()
+}
+
+/// Appends every message as a JSON line: `{"ts", "user", "type", "direction", "text"}`,
+/// with a `to` field for the sent whispers.
+pub struct JsonLog {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    date: NaiveDate, // Day of the messages in the current file
+    rotation: Option<LogRotation>,
+    include_whispers: bool,
+    user_name: String, // Written as the user of the own messages
+}
+
+impl JsonLog {
+    pub fn open(path: &Path, config: &Config) -> io::Result<JsonLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // A file written another day is rotated before writing today
+        let last_write = match metadata.modified() {
+            Ok(time) if metadata.len() > 0 => DateTime::<Local>::from(time),
+            _ => Local::now(),
+        };
+        Ok(JsonLog {
+            path: path.into(),
+            size: metadata.len(),
+            date: last_write.naive_local().date(),
+            file: BufWriter::new(file),
+            rotation: config.json_log_rotation,
+            include_whispers: config.save_whispers,
+            user_name: config.user_name.clone(),
+        })
+    }
+
+    /// Moves the current file to `<path>.1`, shifting the previous ones up to the kept files,
+    /// and starts a new one.
+    fn rotate(&mut self, kept_files: usize) -> io::Result<()> {
+        self.file.flush()?;
+        let rotated_path = |index: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", index));
+            PathBuf::from(path)
+        };
+        match fs::remove_file(rotated_path(kept_files.max(1))) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+        if kept_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..kept_files).rev() {
+                match fs::rename(rotated_path(index), rotated_path(index + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => (),
+                }
+            }
+            fs::rename(&self.path, rotated_path(1))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+
+    pub fn write(&mut self, messages: &[LogMessage]) -> io::Result<()> {
+        for message in messages {
+            let whisper = matches!(
+                message.message_type,
+                MessageType::WhisperReceived(_) | MessageType::WhisperSent(_)
+            );
+            if whisper && !self.include_whispers {
+                continue;
+            }
+            let date = message.date.naive_local().date();
+            if let Some(rotation) = self.rotation {
+                if rotation.daily && date != self.date && self.size > 0 {
+                    self.rotate(rotation.kept_files)?;
+                }
+            }
+            self.date = date;
+            let (message_type, direction, text) = match &message.message_type {
+                MessageType::Connection => ("online", "received", ""),
+                MessageType::Disconnection => ("offline", "received", ""),
+                MessageType::Content(content) => ("message", "received", content.as_str()),
+                MessageType::ContentSent(content) => ("message", "sent", content.as_str()),
+                MessageType::WhisperReceived(content) => {
+                    ("whisper_received", "received", content.as_str())
+                }
+                MessageType::WhisperSent(content) => ("whisper_sent", "sent", content.as_str()),
+                MessageType::Command(input) => ("command", "local", input.as_str()),
+                MessageType::System(content, _) => ("system", "local", content.as_str()),
+            };
+            // The user of a sent whisper in the state is its recipient
+            let (user, recipient) = match &message.message_type {
+                MessageType::WhisperSent(_) => (&self.user_name, Some(&message.user)),
+                MessageType::ContentSent(_) | MessageType::Command(_) => (&self.user_name, None),
+                _ => (&message.user, None),
+            };
+            let recipient = match recipient {
+                Some(recipient) => format!(",\"to\":{}", json_string(recipient)),
+                None => String::new(),
+            };
+            let line = format!(
+                "{{\"ts\":{},\"user\":{},\"type\":\"{}\",\"direction\":\"{}\"{},\"text\":{}}}\n",
+                json_string(&message.date.to_rfc3339()),
+                json_string(user),
+                message_type,
+                direction,
+                recipient,
+                json_string(text),
+            );
+            self.file.write_all(line.as_bytes())?;
+            self.size += line.len() as u64;
+
+            if let Some(rotation) = self.rotation {
+                if matches!(rotation.max_size, Some(max_size) if self.size >= max_size) {
+                    self.rotate(rotation.kept_files)?;
+                }
+            }
+        }
+        // Only flushed once per batch, to not stall the application with many small writes
+        self.file.flush()
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for character in text.chars() {
+        match character {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            character if character.is_control() => {
+                json.push_str(&format!("\\u{:04x}", character as u32))
+            }
+            character => json.push(character),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains(&[',', '"'][..]) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration as DateDuration;
+
+    /// Empty directory for the files of a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("termchat-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn message(text: &str, date: DateTime<Local>) -> LogMessage {
+        let mut message = LogMessage::new("bob".into(), MessageType::Content(text.into()));
+        message.date = date;
+        message
+    }
+
+    fn json_log(path: &Path, rotation: LogRotation) -> JsonLog {
+        let mut config = Config::test();
+        config.json_log_rotation = Some(rotation);
+        JsonLog::open(path, &config).unwrap()
+    }
+
+    #[test]
+    fn json_log_rotated_daily() {
+        let dir = test_dir("json-log-daily");
+        let path = dir.join("log.json");
+        let rotation = LogRotation {
+            max_size: None,
+            daily: true,
+            kept_files: 2,
+        };
+        let mut log = json_log(&path, rotation);
+        let today = Local::now();
+        let yesterday = today - DateDuration::days(1);
+        log.write(&[message("first", yesterday), message("second", yesterday)])
+            .unwrap();
+        log.write(&[message("third", today)]).unwrap();
+        log.write(&[message("fourth", today)]).unwrap();
+
+        let rotated = fs::read_to_string(dir.join("log.json.1")).unwrap();
+        let current = fs::read_to_string(&path).unwrap();
+        assert_eq!(rotated.lines().count(), 2);
+        assert!(rotated.contains("\"text\":\"second\""));
+        assert_eq!(current.lines().count(), 2);
+        assert!(current.contains("\"text\":\"third\""));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_log_rotated_by_size() {
+        let dir = test_dir("json-log-size");
+        let path = dir.join("log.json");
+        let rotation = LogRotation {
+            max_size: Some(1),
+            daily: false,
+            kept_files: 2,
+        };
+        let mut log = json_log(&path, rotation);
+        for text in &["first", "second", "third"] {
+            log.write(&[message(text, Local::now())]).unwrap();
+        }
+
+        // Every line fills the file, the oldest one is deleted
+        assert!(fs::read_to_string(dir.join("log.json.1"))
+            .unwrap()
+            .contains("\"text\":\"third\""));
+        assert!(fs::read_to_string(dir.join("log.json.2"))
+            .unwrap()
+            .contains("\"text\":\"second\""));
+        assert!(!dir.join("log.json.3").exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_log_direction_of_the_messages() {
+        let dir = test_dir("json-log-direction");
+        let path = dir.join("log.json");
+        let mut log = JsonLog::open(&path, &Config::test()).unwrap();
+        // A peer can take the name shown locally for the own user
+        let received = LogMessage::new("alice (me)".into(), MessageType::Content("hi".into()));
+        let sent = LogMessage::new("alice (me)".into(), MessageType::ContentSent("hey".into()));
+        log.write(&[received, sent]).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert!(lines[0].contains("\"user\":\"alice (me)\""));
+        assert!(lines[0].contains("\"direction\":\"received\""));
+        assert!(lines[1].contains(&format!(
+            "\"user\":{}",
+            json_string(&Config::test().user_name)
+        )));
+        assert!(lines[1].contains("\"direction\":\"sent\""));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,13 +1,24 @@
 mod application;
+mod commands;
+mod config;
+mod export;
 mod state;
 mod terminal_events;
 mod ui;
 mod util;
 
 use application::Application;
+use config::{
+    ActivityPanel, ActivityPanelSide, Config, LogRotation, LongMessagePolicy, MessageIcons,
+    MessageRateLimit, PresenceThresholds,
+};
 
 use clap::{App, Arg};
 
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
 fn main() {
     let os_username = whoami::username();
 
@@ -19,6 +30,7 @@ fn main() {
             Arg::with_name("discovery")
                 .long("discovery")
                 .short("d")
+                .env("TERMCHAT_DISCOVERY")
                 .default_value("238.255.0.1:5877")
                 .help("Multicast address to found others 'termchat' applications"),
         )
@@ -26,19 +38,424 @@ fn main() {
             Arg::with_name("username")
                 .long("username")
                 .short("u")
+                .env("TERMCHAT_NAME")
                 .default_value(&os_username)
                 .help("Name used as user idenfication"),
         )
+        .arg(
+            Arg::with_name("tcp-port")
+                .long("tcp-port")
+                .env("TERMCHAT_TCP_PORT")
+                .default_value("0")
+                .help("Port or range of ports (e.g. 5000-5010) to listen for other users, 0 for any free port"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .takes_value(true)
+                .help("Seconds without user input before closing the application (disabled by default)"),
+        )
+        .arg(
+            Arg::with_name("allow")
+                .long("allow")
+                .short("a")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only talk with peers at this IP address (can be used several times)"),
+        )
+        .arg(
+            Arg::with_name("alias")
+                .long("alias")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Show a user with another name in your chat, as <user>=<name> (can be used several times)"),
+        )
+        .arg(
+            Arg::with_name("max-peers")
+                .long("max-peers")
+                .takes_value(true)
+                .help("Maximum of connected users, the new ones are ignored until others leave (no limit by default)"),
+        )
+        .arg(
+            Arg::with_name("offline-buffer")
+                .long("offline-buffer")
+                .takes_value(true)
+                .help("Seconds to keep the messages for a user that just went offline, delivering them if the user reconnects (disabled by default)"),
+        )
+        .arg(
+            Arg::with_name("display-name")
+                .long("display-name")
+                .takes_value(true)
+                .help("Name shown for yourself in your own chat (the username by default)"),
+        )
+        .arg(
+            Arg::with_name("users-panel")
+                .long("users-panel")
+                .help("Show the connected users panel at startup (toggled with F2)"),
+        )
+        .arg(
+            Arg::with_name("idle-after")
+                .long("idle-after")
+                .default_value("120")
+                .help("Seconds without receiving anything from a user before showing it as idle"),
+        )
+        .arg(
+            Arg::with_name("stale-after")
+                .long("stale-after")
+                .default_value("600")
+                .help("Seconds without receiving anything from a user before showing it as stale"),
+        )
+        .arg(
+            Arg::with_name("activity-panel")
+                .long("activity-panel")
+                .takes_value(true)
+                .possible_values(&["right", "below"])
+                .help("Show the system messages in their own panel at this side of the chat"),
+        )
+        .arg(
+            Arg::with_name("chat-percentage")
+                .long("chat-percentage")
+                .default_value("70")
+                .help("Space for the chat when the activity panel is shown"),
+        )
+        .arg(
+            Arg::with_name("compact")
+                .long("compact")
+                .help("Group consecutive messages of the same user at startup (toggled with F3)"),
+        )
+        .arg(
+            Arg::with_name("icons")
+                .long("icons")
+                .takes_value(true)
+                .possible_values(&["unicode", "ascii"])
+                .help("Show before each message whether it was sent, received or its kind"),
+        )
+        .arg(
+            Arg::with_name("message-rate")
+                .long("message-rate")
+                .default_value("10")
+                .help("Messages per second accepted from each peer, 0 for no limit"),
+        )
+        .arg(
+            Arg::with_name("message-burst")
+                .long("message-burst")
+                .default_value("20")
+                .help("Messages accepted at once from each peer before applying the rate"),
+        )
+        .arg(
+            Arg::with_name("max-message-length")
+                .long("max-message-length")
+                .default_value("4096")
+                .help("Characters accepted in a received message"),
+        )
+        .arg(
+            Arg::with_name("long-messages")
+                .long("long-messages")
+                .possible_values(&["truncate", "drop"])
+                .default_value("truncate")
+                .help("What to do with a received message longer than the maximum"),
+        )
+        .arg(
+            Arg::with_name("json-log")
+                .long("json-log")
+                .takes_value(true)
+                .help("File where every message is appended as a JSON line, for other tools"),
+        )
+        .arg(
+            Arg::with_name("json-log-max-size")
+                .long("json-log-max-size")
+                .takes_value(true)
+                .help("MiB of the JSON log before moving it to <file>.1 and starting a new one (no limit by default)"),
+        )
+        .arg(
+            Arg::with_name("json-log-daily")
+                .long("json-log-daily")
+                .help("Also move the JSON log to <file>.1 when the day changes"),
+        )
+        .arg(
+            Arg::with_name("json-log-keep")
+                .long("json-log-keep")
+                .default_value("5")
+                .help("Previous JSON logs kept when it is limited or daily, the older ones are deleted"),
+        )
+        .arg(
+            Arg::with_name("save-whispers")
+                .long("save-whispers")
+                .help("Include the whispers in the transcripts written by the save command and in the JSON log"),
+        )
+        .arg(
+            Arg::with_name("signature")
+                .long("signature")
+                .takes_value(true)
+                .help("Text appended to every message you send to all users"),
+        )
+        .arg(
+            Arg::with_name("status")
+                .long("status")
+                .takes_value(true)
+                .help("Status shown to the other users next to your name (changed with the status command)"),
+        )
+        .arg(
+            Arg::with_name("motd")
+                .long("motd")
+                .takes_value(true)
+                .help("Message of the day shown to the users that join after you"),
+        )
+        .arg(
+            Arg::with_name("prompt")
+                .long("prompt")
+                .takes_value(true)
+                .help("Text shown before your input, e.g. '> '"),
+        )
+        .arg(
+            Arg::with_name("loopback")
+                .long("loopback")
+                .help("Receive your own messages from a user called 'self', to try termchat alone"),
+        )
+        .arg(
+            Arg::with_name("command-prefix")
+                .long("command-prefix")
+                .default_value("?")
+                .help("Character that starts a command"),
+        )
+        .arg(
+            Arg::with_name("unknown-commands-as-text")
+                .long("unknown-commands-as-text")
+                .help("Send lines starting by an unknown command as messages instead of reporting an error"),
+        )
         .get_matches();
 
-    let addr = match matches.value_of("discovery").unwrap().parse() {
-        Ok(addr) => addr,
-        Err(_) => return eprintln!("'discovery' must be a valid multicast address"),
+    let discovery_addr = match matches.value_of("discovery").unwrap().parse::<SocketAddr>() {
+        Ok(addr) if addr.is_ipv4() && addr.ip().is_multicast() => addr,
+        _ => return eprintln!("'discovery' must be a valid multicast address"),
+    };
+
+    let tcp_ports = match util::parse_port_range(matches.value_of("tcp-port").unwrap()) {
+        Some(ports) => ports,
+        None => return eprintln!("'tcp-port' must be a port number or a range like 5000-5010"),
+    };
+
+    let idle_timeout = match matches.value_of("idle-timeout").map(|secs| secs.parse()) {
+        None => None,
+        Some(Ok(secs)) if secs > 0 => Some(Duration::from_secs(secs)),
+        Some(_) => return eprintln!("'idle-timeout' must be a positive number of seconds"),
+    };
+
+    let user_name = match util::sanitize_user_name(matches.value_of("username").unwrap()) {
+        Some(user_name) => user_name,
+        None => return eprintln!("'username' must contain at least one printable character"),
+    };
+
+    let max_peers = match matches.value_of("max-peers").map(|peers| peers.parse()) {
+        None => None,
+        Some(Ok(peers)) if peers > 0 => Some(peers),
+        Some(_) => return eprintln!("'max-peers' must be a positive number of users"),
+    };
+
+    let offline_buffer_ttl = match matches.value_of("offline-buffer").map(|secs| secs.parse()) {
+        None => None,
+        Some(Ok(secs)) if secs > 0 => Some(Duration::from_secs(secs)),
+        Some(_) => return eprintln!("'offline-buffer' must be a positive number of seconds"),
+    };
+
+    let idle_after = match matches.value_of("idle-after").unwrap().parse() {
+        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+        _ => return eprintln!("'idle-after' must be a positive number of seconds"),
+    };
+
+    let stale_after = match matches.value_of("stale-after").unwrap().parse() {
+        Ok(secs) if Duration::from_secs(secs) > idle_after => Duration::from_secs(secs),
+        _ => {
+            return eprintln!("'stale-after' must be a number of seconds greater than 'idle-after'")
+        }
+    };
+
+    let chat_percentage = match matches.value_of("chat-percentage").unwrap().parse() {
+        Ok(percentage) if (10..=90).contains(&percentage) => percentage,
+        _ => return eprintln!("'chat-percentage' must be a number between 10 and 90"),
+    };
+
+    let activity_panel = matches
+        .value_of("activity-panel")
+        .map(|side| ActivityPanel {
+            side: match side {
+                "right" => ActivityPanelSide::Right,
+                _ => ActivityPanelSide::Below,
+            },
+            chat_percentage,
+        });
+
+    let message_icons = matches.value_of("icons").map(|icons| match icons {
+        "ascii" => MessageIcons::Ascii,
+        _ => MessageIcons::Unicode,
+    });
+
+    let message_rate = match matches.value_of("message-rate").unwrap().parse::<f64>() {
+        Ok(rate) if rate >= 0.0 => rate,
+        _ => return eprintln!("'message-rate' must be a positive number"),
+    };
+
+    let message_burst = match matches.value_of("message-burst").unwrap().parse::<u32>() {
+        Ok(burst) if burst > 0 => burst,
+        _ => return eprintln!("'message-burst' must be a positive integer"),
+    };
+
+    let message_rate_limit = if message_rate > 0.0 {
+        Some(MessageRateLimit {
+            rate: message_rate,
+            burst: message_burst,
+        })
+    } else {
+        None
     };
 
-    let name = matches.value_of("username").unwrap();
+    let max_message_length = match matches.value_of("max-message-length").unwrap().parse() {
+        Ok(length) if length > 0 => length,
+        _ => return eprintln!("'max-message-length' must be a positive integer"),
+    };
+
+    let long_message_policy = match matches.value_of("long-messages").unwrap() {
+        "drop" => LongMessagePolicy::Drop,
+        _ => LongMessagePolicy::Truncate,
+    };
+
+    let json_log_kept_files = match matches.value_of("json-log-keep").unwrap().parse() {
+        Ok(kept_files) => kept_files,
+        Err(_) => return eprintln!("'json-log-keep' must be a number of files"),
+    };
+
+    let json_log_max_size = match matches
+        .value_of("json-log-max-size")
+        .map(|mib| mib.parse::<u64>())
+    {
+        None => None,
+        Some(Ok(mib)) if mib > 0 => Some(mib * 1024 * 1024),
+        Some(_) => return eprintln!("'json-log-max-size' must be a positive number of MiB"),
+    };
+
+    let json_log_daily = matches.is_present("json-log-daily");
+    let json_log_rotation = if json_log_max_size.is_some() || json_log_daily {
+        Some(LogRotation {
+            max_size: json_log_max_size,
+            daily: json_log_daily,
+            kept_files: json_log_kept_files,
+        })
+    } else {
+        None
+    };
+
+    let allowed_peers = match matches.values_of("allow") {
+        None => None,
+        Some(ips) => match ips.map(|ip| ip.parse()).collect::<Result<Vec<_>, _>>() {
+            Ok(ips) => Some(ips),
+            Err(_) => return eprintln!("'allow' must be a valid IP address"),
+        },
+    };
+
+    let mut aliases = Vec::new();
+    for alias in matches.values_of("alias").into_iter().flatten() {
+        let (user, alias) = match alias.find('=') {
+            Some(separator) => (&alias[..separator], &alias[separator + 1..]),
+            None => return eprintln!("'alias' must be written as <user>=<name>"),
+        };
+        match (
+            util::sanitize_user_name(user),
+            util::sanitize_user_name(alias),
+        ) {
+            (Some(_), Some(alias)) if aliases.iter().any(|(_, used)| *used == alias) => {
+                return eprintln!("'alias' {} is given to more than one user", alias)
+            }
+            (Some(user), Some(alias)) => aliases.push((user, alias)),
+            _ => return eprintln!("'alias' names must contain at least one printable character"),
+        }
+    }
+
+    let display_name = match matches.value_of("display-name") {
+        None => user_name.clone(),
+        Some(name) => match util::sanitize_user_name(name) {
+            Some(display_name) => display_name,
+            None => {
+                return eprintln!("'display-name' must contain at least one printable character")
+            }
+        },
+    };
+
+    let signature = match matches.value_of("signature").map(str::trim) {
+        None | Some("") => None,
+        Some(signature) if signature.chars().any(char::is_control) => {
+            return eprintln!("'signature' must not contain control characters")
+        }
+        Some(signature) => Some(signature.to_string()),
+    };
+
+    let status = match matches.value_of("status") {
+        None => None,
+        Some(status) => match util::sanitize_status(status) {
+            Some(status) => Some(status),
+            None => return eprintln!("'status' must contain at least one printable character"),
+        },
+    };
+
+    let motd = match matches.value_of("motd") {
+        None => None,
+        Some(motd) => match util::sanitize_motd(motd) {
+            Some(motd) => Some(motd),
+            None => return eprintln!("'motd' must contain at least one printable character"),
+        },
+    };
 
-    if let Ok(mut app) = Application::new(addr, &name) {
-        app.run()
+    let input_prompt = matches.value_of("prompt").unwrap_or("");
+    if !input_prompt.is_ascii() || input_prompt.chars().any(|c| c.is_ascii_control()) {
+        return eprintln!("'prompt' must only contain printable ASCII characters");
+    }
+
+    let mut command_prefix = matches.value_of("command-prefix").unwrap().chars();
+    let command_prefix = match (command_prefix.next(), command_prefix.next()) {
+        (Some(prefix), None) if !prefix.is_alphanumeric() && !prefix.is_whitespace() => prefix,
+        _ => return eprintln!("'command-prefix' must be a single symbol character"),
+    };
+
+    let config = Config {
+        discovery_addr,
+        tcp_ports,
+        user_name,
+        display_name,
+        signature,
+        status,
+        motd,
+        input_prompt: input_prompt.into(),
+        command_prefix,
+        unknown_commands_as_text: matches.is_present("unknown-commands-as-text"),
+        idle_timeout,
+        allowed_peers,
+        aliases,
+        max_peers,
+        max_message_length,
+        long_message_policy,
+        message_rate_limit,
+        offline_buffer_ttl,
+        show_users_panel: matches.is_present("users-panel"),
+        activity_panel,
+        loopback: matches.is_present("loopback"),
+        presence_thresholds: PresenceThresholds {
+            idle: idle_after,
+            stale: stale_after,
+        },
+        compact_layout: matches.is_present("compact"),
+        message_icons,
+        json_log: matches.value_of("json-log").map(PathBuf::from),
+        json_log_rotation,
+        save_whispers: matches.is_present("save-whispers"),
+    };
+
+    // The application is dropped before reporting, so the message is shown out of the UI
+    let result = match Application::new(config) {
+        Ok(mut app) => app.run(),
+        Err(err) => return eprintln!("termchat could not start: {}", err),
+    };
+    if let Err(err) = result {
+        eprintln!("termchat stopped, the terminal could not be drawn: {}", err);
     }
 }
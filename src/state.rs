@@ -1,13 +1,43 @@
+use super::config::MessageRateLimit;
+use super::util::TokenBucket;
+
 use message_io::network::Endpoint;
 
 use chrono::{DateTime, Local};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+const MAX_OFFLINE_MESSAGES: usize = 100;
+const MAX_EARLY_MESSAGES: usize = 100;
+const MAX_INPUT_UNDO: usize = 100;
+// Consecutive drops before ignoring the peer.
+// Above MAX_OFFLINE_MESSAGES, so a peer delivering them all at once is not taken as flooding.
+const MAX_FLOODING_DROPS: usize = 2 * MAX_OFFLINE_MESSAGES;
 
 pub enum MessageType {
     Connection,
     Disconnection,
-    Content(String),
+    Content(String),         // content, the user is the sender
+    ContentSent(String),     // content, the user is the own one
+    WhisperReceived(String), // content, the user is the sender
+    WhisperSent(String),     // content, the user is the recipient
+    Command(String),         // input, only shown locally
+    System(String, SystemMessageType),
+}
+
+pub enum SystemMessageType {
+    Info,
+    Warning,
+    Error,
+}
+
+/// How the messages are shown.
+pub enum MessageLayout {
+    Full,    // Date and user in every message
+    Compact, // Consecutive messages of the same user are grouped under the first one
 }
 
 pub struct LogMessage {
@@ -26,14 +56,100 @@ impl LogMessage {
     }
 }
 
+/// Counts the data discarded by the application.
+#[derive(Default)]
+pub struct DropCounters {
+    pub messages: usize,
+    pub peers: usize,
+}
+
+/// Another application, as it introduced itself.
+#[derive(Clone)]
+pub struct Peer {
+    pub user: String,
+    pub server_port: Option<u16>, // None if not told, by older versions
+    pub version: u16,             // of the protocol
+}
+
+/// Lifecycle of the connection with another application.
+/// An endpoint is not tracked before connecting nor after disconnecting.
+pub enum PeerState {
+    Connecting(Peer),              // Before introducing ourselves to it
+    HandshakePending(Vec<String>), // Messages received before the user introduced itself
+    Established(Peer),
+    Disconnecting, // Closed here, its events still queued are ignored
+}
+
+impl PeerState {
+    fn name(&self) -> &'static str {
+        match self {
+            PeerState::Connecting(_) => "Connecting",
+            PeerState::HandshakePending(_) => "HandshakePending",
+            PeerState::Established(_) => "Established",
+            PeerState::Disconnecting => "Disconnecting",
+        }
+    }
+}
+
+/// Room for a new user under a maximum of connected users.
+pub enum PeerLimit {
+    Available,
+    Reached, // Full for the first time since a user left, to be reported
+    Full,
+}
+
+pub enum MessageRate {
+    Accepted,
+    Dropped,
+    Flooding, // The peer has just been ignored
+    Ignored,
+}
+
+struct MessageLimiter {
+    bucket: TokenBucket,
+    consecutive_drops: usize,
+}
+
+struct OfflineUser {
+    disconnection_time: Instant,
+    pending_messages: Vec<String>,
+}
+
+/// Last change of the input, consecutive changes of the same kind are undone together.
+#[derive(Clone, Copy, PartialEq)]
+enum InputEdit {
+    None,
+    Write,
+    Remove,
+}
+
 pub struct ApplicationState {
     messages: Vec<LogMessage>,
     scroll_messages_view: usize,
+    scroll_activity_view: usize,
+    activity_panel: bool, // System messages are not in the messages view
     input: String,
     input_cursor: usize,
-    lan_users: HashMap<Endpoint, String>,
+    input_undo: Vec<(String, usize)>, // input, cursor
+    input_redo: Vec<(String, usize)>,
+    last_input_edit: InputEdit,
+    peers: HashMap<Endpoint, PeerState>,
+    last_peer_activity: HashMap<Endpoint, Instant>, // Only for established users
+    user_statuses: HashMap<Endpoint, String>,
     users_id: HashMap<String, usize>,
     last_user_id: usize,
+    offline_users: HashMap<String, OfflineUser>,
+    drop_counters: DropCounters,
+    last_whisperer: Option<String>,
+    aliases: HashMap<String, String>, // user, local name shown instead
+    users_panel_visible: bool,
+    debug: bool, // Network events are shown as system messages
+    error_flash: bool,
+    unseen_errors: usize, // Errors added while the newest messages were scrolled out of view
+    message_layout: MessageLayout,
+    message_limiters: HashMap<Endpoint, MessageLimiter>,
+    ignored_endpoints: HashSet<Endpoint>,
+    peer_limit_reported: bool,
 }
 
 pub enum CursorMovement {
@@ -54,11 +170,30 @@ impl ApplicationState {
         ApplicationState {
             messages: Vec::new(),
             scroll_messages_view: 0,
+            scroll_activity_view: 0,
+            activity_panel: false,
             input: String::new(),
             input_cursor: 0,
-            lan_users: HashMap::new(),
+            input_undo: Vec::new(),
+            input_redo: Vec::new(),
+            last_input_edit: InputEdit::None,
+            peers: HashMap::new(),
+            last_peer_activity: HashMap::new(),
+            user_statuses: HashMap::new(),
             users_id: HashMap::new(),
             last_user_id: 0,
+            offline_users: HashMap::new(),
+            drop_counters: DropCounters::default(),
+            last_whisperer: None,
+            aliases: HashMap::new(),
+            users_panel_visible: false,
+            debug: false,
+            error_flash: false,
+            unseen_errors: 0,
+            message_layout: MessageLayout::Full,
+            message_limiters: HashMap::new(),
+            ignored_endpoints: HashSet::new(),
+            peer_limit_reported: false,
         }
     }
 
@@ -70,68 +205,525 @@ impl ApplicationState {
         self.scroll_messages_view
     }
 
+    pub fn scroll_activity_view(&self) -> usize {
+        self.scroll_activity_view
+    }
+
+    pub fn enable_activity_panel(&mut self) {
+        self.activity_panel = true;
+    }
+
     pub fn input(&self) -> &str {
         &self.input
     }
 
+    /// Position of the cursor in characters.
     pub fn input_cursor(&self) -> usize {
-        self.input_cursor
+        self.input[..self.input_cursor].chars().count()
     }
 
     pub fn user_name(&self, endpoint: Endpoint) -> Option<&String> {
-        self.lan_users.get(&endpoint)
+        match self.peers.get(&endpoint) {
+            Some(PeerState::Established(peer)) => Some(&peer.user),
+            _ => None,
+        }
+    }
+
+    /// Version of the protocol spoken by the peer, 0 if it is still unknown.
+    pub fn peer_version(&self, endpoint: Endpoint) -> u16 {
+        match self.peers.get(&endpoint) {
+            Some(PeerState::Connecting(peer)) | Some(PeerState::Established(peer)) => peer.version,
+            _ => 0,
+        }
+    }
+
+    pub fn peer_state(&self, endpoint: Endpoint) -> Option<&PeerState> {
+        self.peers.get(&endpoint)
+    }
+
+    /// Every open connection, also the ones whose user is still unknown.
+    pub fn all_peer_endpoints(&self) -> impl Iterator<Item = &Endpoint> {
+        self.peers
+            .iter()
+            .filter_map(|(endpoint, peer_state)| match peer_state {
+                PeerState::Disconnecting => None,
+                _ => Some(endpoint),
+            })
     }
 
     pub fn all_user_endpoints(&self) -> impl Iterator<Item = &Endpoint> {
-        self.lan_users.keys()
+        self.peers
+            .iter()
+            .filter_map(|(endpoint, peer_state)| match peer_state {
+                PeerState::Established(_) => Some(endpoint),
+                _ => None,
+            })
+    }
+
+    /// Endpoints of the user, also the ones still connecting to it.
+    pub fn user_endpoints<'a>(&'a self, user: &'a str) -> impl Iterator<Item = &'a Endpoint> {
+        self.peers
+            .iter()
+            .filter_map(move |(endpoint, peer_state)| match peer_state {
+                PeerState::Connecting(peer) | PeerState::Established(peer) if peer.user == user => {
+                    Some(endpoint)
+                }
+                _ => None,
+            })
+    }
+
+    /// Endpoints of the application listening at the address,
+    /// either connected by us or connected to us.
+    pub fn server_endpoints(&self, server_addr: SocketAddr) -> Vec<Endpoint> {
+        self.peers
+            .iter()
+            .filter_map(|(endpoint, peer_state)| match peer_state {
+                PeerState::Connecting(peer) | PeerState::Established(peer)
+                    if endpoint.addr().ip() == server_addr.ip()
+                        && peer.server_port == Some(server_addr.port()) =>
+                {
+                    Some(*endpoint)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Every user with the time passed since something was received from it.
+    pub fn all_users_activity(&self) -> impl Iterator<Item = (Endpoint, &String, Duration)> {
+        self.all_user_endpoints().filter_map(move |endpoint| {
+            let user = self.user_name(*endpoint)?;
+            let silence = self.last_peer_activity.get(endpoint)?.elapsed();
+            Some((*endpoint, user, silence))
+        })
+    }
+
+    pub fn user_status(&self, endpoint: Endpoint) -> Option<&String> {
+        self.user_statuses.get(&endpoint)
+    }
+
+    pub fn set_user_status(&mut self, endpoint: Endpoint, status: Option<String>) {
+        match status {
+            Some(status) => self.user_statuses.insert(endpoint, status),
+            None => self.user_statuses.remove(&endpoint),
+        };
+    }
+
+    pub fn users_panel_visible(&self) -> bool {
+        self.users_panel_visible
+    }
+
+    pub fn toggle_users_panel(&mut self) {
+        self.users_panel_visible = !self.users_panel_visible;
+    }
+
+    pub fn debug(&self) -> bool {
+        self.debug
+    }
+
+    pub fn toggle_debug(&mut self) {
+        self.debug = !self.debug;
+    }
+
+    pub fn error_flash(&self) -> bool {
+        self.error_flash
+    }
+
+    pub fn end_error_flash(&mut self) {
+        self.error_flash = false;
+    }
+
+    pub fn unseen_errors(&self) -> usize {
+        self.unseen_errors
+    }
+
+    pub fn message_layout(&self) -> &MessageLayout {
+        &self.message_layout
+    }
+
+    pub fn toggle_message_layout(&mut self) {
+        self.message_layout = match self.message_layout {
+            MessageLayout::Full => MessageLayout::Compact,
+            MessageLayout::Compact => MessageLayout::Full,
+        };
     }
 
     pub fn users_id(&self) -> &HashMap<String, usize> {
         &self.users_id
     }
 
-    pub fn connected_user(&mut self, endpoint: Endpoint, user: &str) {
-        self.lan_users.insert(endpoint, user.into());
-        if !self.users_id.contains_key(user) {
-            self.users_id.insert(user.into(), self.last_user_id);
+    pub fn drop_counters(&self) -> &DropCounters {
+        &self.drop_counters
+    }
+
+    pub fn count_rejected_peer(&mut self) {
+        self.drop_counters.peers += 1;
+    }
+
+    pub fn count_dropped_message(&mut self) {
+        self.drop_counters.messages += 1;
+    }
+
+    /// Name shown for the user: its local alias, if any.
+    pub fn shown_name<'a>(&'a self, user: &'a str) -> &'a str {
+        self.aliases.get(user).map_or(user, String::as_str)
+    }
+
+    /// User with that name or local alias.
+    pub fn user_by_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases
+            .iter()
+            .find(|(_, alias)| *alias == name)
+            .map_or(name, |(user, _)| user.as_str())
+    }
+
+    /// Checks that the alias does not name another user, by its name or its alias.
+    pub fn is_alias_available(&self, user: &str, alias: &str) -> bool {
+        let other_user = alias != user && self.users_id.contains_key(alias);
+        let other_alias = self
+            .aliases
+            .iter()
+            .any(|(aliased_user, used)| aliased_user != user && used == alias);
+        !other_user && !other_alias
+    }
+
+    pub fn set_alias(&mut self, user: String, alias: Option<String>) {
+        match alias {
+            Some(alias) => self.aliases.insert(user, alias),
+            None => self.aliases.remove(&user),
+        };
+    }
+
+    pub fn last_whisperer(&self) -> Option<&String> {
+        self.last_whisperer.as_ref()
+    }
+
+    pub fn whisper_received(&mut self, user: &str, content: String) {
+        self.last_whisperer = Some(user.into());
+        self.add_message(LogMessage::new(
+            user.into(),
+            MessageType::WhisperReceived(content),
+        ));
+    }
+
+    /// Changes the state of the endpoint, `None` to stop tracking it.
+    /// The transitions are shown in debug mode.
+    fn set_peer_state(
+        &mut self,
+        endpoint: Endpoint,
+        peer_state: Option<PeerState>,
+    ) -> Option<PeerState> {
+        let next = peer_state.as_ref().map_or("Untracked", PeerState::name);
+        let previous = match peer_state {
+            Some(peer_state) => self.peers.insert(endpoint, peer_state),
+            None => self.peers.remove(&endpoint),
+        };
+        if self.debug && (previous.is_some() || next != "Untracked") {
+            let info = format!(
+                "Peer {}: {} -> {}",
+                endpoint.addr(),
+                previous.as_ref().map_or("Untracked", PeerState::name),
+                next
+            );
+            self.add_system_message(info, SystemMessageType::Info);
+        }
+        previous
+    }
+
+    /// A connection was accepted but its user is still unknown.
+    pub fn pending_peer(&mut self, endpoint: Endpoint) {
+        if !self.peers.contains_key(&endpoint) {
+            self.set_peer_state(endpoint, Some(PeerState::HandshakePending(Vec::new())));
+        }
+    }
+
+    /// A connection was opened to the user, that still does not know who we are.
+    pub fn connecting_user(&mut self, endpoint: Endpoint, peer: Peer) {
+        self.set_peer_state(endpoint, Some(PeerState::Connecting(peer)));
+    }
+
+    pub fn connected_user(&mut self, endpoint: Endpoint, peer: Peer) {
+        let user = peer.user.clone();
+        self.set_peer_state(endpoint, Some(PeerState::Established(peer)));
+        self.last_peer_activity.insert(endpoint, Instant::now());
+        if !self.users_id.contains_key(&user) {
+            self.users_id.insert(user.clone(), self.last_user_id);
         }
         self.last_user_id += 1;
-        self.add_message(LogMessage::new(user.into(), MessageType::Connection));
+        self.add_message(LogMessage::new(user, MessageType::Connection));
     }
 
     pub fn disconnected_user(&mut self, endpoint: Endpoint) {
-        let user = self.lan_users.remove(&endpoint).unwrap();
-        self.add_message(LogMessage::new(user, MessageType::Disconnection));
+        self.release_peer(endpoint, None);
+    }
+
+    fn release_peer(&mut self, endpoint: Endpoint, peer_state: Option<PeerState>) {
+        self.message_limiters.remove(&endpoint);
+        self.ignored_endpoints.remove(&endpoint);
+        self.last_peer_activity.remove(&endpoint);
+        self.user_statuses.remove(&endpoint);
+        // The endpoint could belong to a connection that never was a user
+        match self.set_peer_state(endpoint, peer_state) {
+            Some(PeerState::Established(peer)) => {
+                self.add_message(LogMessage::new(peer.user, MessageType::Disconnection));
+                self.peer_limit_reported = false;
+            }
+            Some(PeerState::HandshakePending(messages)) => {
+                self.drop_counters.messages += messages.len();
+            }
+            _ => (),
+        }
+    }
+
+    /// Removes the peer, keeping its user as offline if `offline_buffer`,
+    /// so it receives the messages sent meanwhile when reconnecting.
+    pub fn remove_peer(&mut self, endpoint: Endpoint, offline_buffer: bool) {
+        self.keep_offline_user(endpoint, offline_buffer);
+        self.disconnected_user(endpoint);
+    }
+
+    /// Same as `remove_peer` for a connection closed here.
+    /// It stays `Disconnecting` until `disconnected_user`, once its queued events are processed.
+    pub fn disconnecting_peer(&mut self, endpoint: Endpoint, offline_buffer: bool) {
+        self.keep_offline_user(endpoint, offline_buffer);
+        self.release_peer(endpoint, Some(PeerState::Disconnecting));
+    }
+
+    fn keep_offline_user(&mut self, endpoint: Endpoint, offline_buffer: bool) {
+        if offline_buffer {
+            if let Some(user) = self.user_name(endpoint).cloned() {
+                self.buffer_offline_user(user);
+            }
+        }
+    }
+
+    /// Disconnects the peers that failed to receive a message, reporting each of them.
+    pub fn remove_unreachable_peers(
+        &mut self,
+        failures: Vec<(Endpoint, io::Error)>,
+        offline_buffer: bool,
+    ) {
+        for (endpoint, err) in failures {
+            let warning = format!(
+                "Removed {}, it could not be reached: {}",
+                endpoint.addr(),
+                err
+            );
+            self.add_system_message(warning, SystemMessageType::Warning);
+            self.disconnecting_peer(endpoint, offline_buffer);
+        }
+    }
+
+    /// Checks if a new user fits in the maximum, not counting the excluded endpoint.
+    pub fn peer_limit(&mut self, max_peers: usize, excluded: Option<Endpoint>) -> PeerLimit {
+        let peers = self
+            .all_user_endpoints()
+            .filter(|endpoint| Some(**endpoint) != excluded)
+            .count();
+        if peers < max_peers {
+            PeerLimit::Available
+        } else if !self.peer_limit_reported {
+            self.peer_limit_reported = true;
+            PeerLimit::Reached
+        } else {
+            PeerLimit::Full
+        }
+    }
+
+    pub fn peer_activity(&mut self, endpoint: Endpoint) {
+        if let Some(last_activity) = self.last_peer_activity.get_mut(&endpoint) {
+            *last_activity = Instant::now();
+        }
+    }
+
+    /// Checks if a message from the endpoint is within the rate limit.
+    /// A peer that keeps exceeding the limit is ignored from then on.
+    pub fn limit_message_rate(
+        &mut self,
+        endpoint: Endpoint,
+        limit: &MessageRateLimit,
+    ) -> MessageRate {
+        if self.ignored_endpoints.contains(&endpoint) {
+            self.drop_counters.messages += 1;
+            return MessageRate::Ignored;
+        }
+
+        let limiter = self
+            .message_limiters
+            .entry(endpoint)
+            .or_insert_with(|| MessageLimiter {
+                bucket: TokenBucket::new(limit.rate, limit.burst),
+                consecutive_drops: 0,
+            });
+
+        if limiter.bucket.take() {
+            limiter.consecutive_drops = 0;
+            return MessageRate::Accepted;
+        }
+
+        self.drop_counters.messages += 1;
+        limiter.consecutive_drops += 1;
+        if limiter.consecutive_drops < MAX_FLOODING_DROPS {
+            MessageRate::Dropped
+        } else {
+            self.message_limiters.remove(&endpoint);
+            self.ignored_endpoints.insert(endpoint);
+            MessageRate::Flooding
+        }
+    }
+
+    /// Keeps a message received before knowing the user of the endpoint.
+    /// Messages beyond `MAX_EARLY_MESSAGES` are discarded.
+    pub fn buffer_early_message(&mut self, endpoint: Endpoint, content: String) {
+        self.pending_peer(endpoint);
+        match self.peers.get_mut(&endpoint) {
+            Some(PeerState::HandshakePending(messages)) if messages.len() < MAX_EARLY_MESSAGES => {
+                messages.push(content);
+            }
+            _ => self.drop_counters.messages += 1,
+        }
+    }
+
+    pub fn take_early_messages(&mut self, endpoint: Endpoint) -> Vec<String> {
+        match self.peers.get_mut(&endpoint) {
+            Some(PeerState::HandshakePending(messages)) => std::mem::take(messages),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Starts keeping the messages sent while the user is offline.
+    pub fn buffer_offline_user(&mut self, user: String) {
+        let offline_user = OfflineUser {
+            disconnection_time: Instant::now(),
+            pending_messages: Vec::new(),
+        };
+        self.offline_users.insert(user, offline_user);
+    }
+
+    /// Keeps the message for the users that were disconnected less than `ttl` ago.
+    /// Users exceeding that time or the maximum of pending messages are forgotten.
+    pub fn store_offline_message(&mut self, content: &str, ttl: Duration) {
+        let drop_counters = &mut self.drop_counters;
+        self.offline_users.retain(|_, offline_user| {
+            let keep = offline_user.disconnection_time.elapsed() < ttl
+                && offline_user.pending_messages.len() < MAX_OFFLINE_MESSAGES;
+            if !keep {
+                drop_counters.messages += offline_user.pending_messages.len();
+            }
+            keep
+        });
+        for offline_user in self.offline_users.values_mut() {
+            offline_user.pending_messages.push(content.into());
+        }
+    }
+
+    /// Returns the messages stored while the user was offline, if they have not expired.
+    pub fn take_offline_messages(&mut self, user: &str, ttl: Duration) -> Vec<String> {
+        match self.offline_users.remove(user) {
+            Some(offline_user) if offline_user.disconnection_time.elapsed() < ttl => {
+                offline_user.pending_messages
+            }
+            Some(offline_user) => {
+                self.drop_counters.messages += offline_user.pending_messages.len();
+                Vec::new()
+            }
+            None => Vec::new(),
+        }
     }
 
     pub fn input_write(&mut self, character: char) {
+        // A new word starts a new undo step
+        self.save_input_undo(InputEdit::Write, character.is_whitespace());
         self.input.insert(self.input_cursor, character);
-        self.input_cursor += 1;
+        self.input_cursor += character.len_utf8();
     }
 
     pub fn input_remove(&mut self) {
         if self.input_cursor < self.input.len() {
+            self.save_input_undo(InputEdit::Remove, false);
             self.input.remove(self.input_cursor);
         }
     }
 
     pub fn input_remove_previous(&mut self) {
-        if self.input_cursor > 0 {
-            self.input_cursor -= 1;
+        if let Some(previous) = self.input[..self.input_cursor].chars().next_back() {
+            self.save_input_undo(InputEdit::Remove, false);
+            self.input_cursor -= previous.len_utf8();
             self.input.remove(self.input_cursor);
         }
     }
 
+    /// Removes the word before the cursor, with the spaces between them.
+    pub fn input_remove_previous_word(&mut self) {
+        let word_start = self.input[..self.input_cursor]
+            .trim_end()
+            .char_indices()
+            .rev()
+            .find(|(_, character)| character.is_whitespace())
+            .map_or(0, |(index, character)| index + character.len_utf8());
+        self.input_remove_range(word_start, self.input_cursor);
+    }
+
+    pub fn input_remove_to_start(&mut self) {
+        self.input_remove_range(0, self.input_cursor);
+    }
+
+    pub fn input_remove_to_end(&mut self) {
+        self.input_remove_range(self.input_cursor, self.input.len());
+    }
+
+    fn input_remove_range(&mut self, start: usize, end: usize) {
+        if start < end {
+            // Each removal of several characters can be undone alone
+            self.save_input_undo(InputEdit::Remove, true);
+            self.input.replace_range(start..end, "");
+            self.input_cursor = start;
+        }
+    }
+
+    /// Keeps the input before the edit if it starts a new undo step.
+    fn save_input_undo(&mut self, edit: InputEdit, new_step: bool) {
+        if edit != self.last_input_edit || new_step {
+            if self.input_undo.len() == MAX_INPUT_UNDO {
+                self.input_undo.remove(0);
+            }
+            self.input_undo
+                .push((self.input.clone(), self.input_cursor));
+        }
+        self.input_redo.clear();
+        self.last_input_edit = edit;
+    }
+
+    pub fn input_undo(&mut self) {
+        if let Some((input, cursor)) = self.input_undo.pop() {
+            let current = std::mem::replace(&mut self.input, input);
+            self.input_redo.push((current, self.input_cursor));
+            self.input_cursor = cursor;
+            self.last_input_edit = InputEdit::None;
+        }
+    }
+
+    pub fn input_redo(&mut self) {
+        if let Some((input, cursor)) = self.input_redo.pop() {
+            let current = std::mem::replace(&mut self.input, input);
+            self.input_undo.push((current, self.input_cursor));
+            self.input_cursor = cursor;
+            self.last_input_edit = InputEdit::None;
+        }
+    }
+
     pub fn input_move_cursor(&mut self, movement: CursorMovement) {
+        self.last_input_edit = InputEdit::None;
         match movement {
+            // The cursor is a byte index, moved by whole characters
             CursorMovement::Left => {
-                if self.input_cursor > 0 {
-                    self.input_cursor -= 1;
+                if let Some(previous) = self.input[..self.input_cursor].chars().next_back() {
+                    self.input_cursor -= previous.len_utf8();
                 }
             }
             CursorMovement::Right => {
-                if self.input_cursor < self.input.len() {
-                    self.input_cursor += 1;
+                if let Some(next) = self.input[self.input_cursor..].chars().next() {
+                    self.input_cursor += next.len_utf8();
                 }
             }
             CursorMovement::Start => {
@@ -144,24 +736,34 @@ impl ApplicationState {
     }
 
     pub fn messages_scroll(&mut self, movement: ScrollMovement) {
-        match movement {
-            ScrollMovement::Up => {
-                if self.scroll_messages_view > 0 {
-                    self.scroll_messages_view -= 1;
-                }
-            }
-            ScrollMovement::Down => {
-                self.scroll_messages_view += 1;
-            }
-            ScrollMovement::Start => {
-                self.scroll_messages_view += 0;
-            }
+        scroll_view(&mut self.scroll_messages_view, movement);
+        if self.errors_view() == 0 {
+            self.unseen_errors = 0;
+        }
+    }
+
+    pub fn activity_scroll(&mut self, movement: ScrollMovement) {
+        scroll_view(&mut self.scroll_activity_view, movement);
+        if self.errors_view() == 0 {
+            self.unseen_errors = 0;
+        }
+    }
+
+    /// Scroll of the view where the errors are shown.
+    fn errors_view(&self) -> usize {
+        if self.activity_panel {
+            self.scroll_activity_view
+        } else {
+            self.scroll_messages_view
         }
     }
 
     pub fn reset_input(&mut self) -> Option<String> {
         if !self.input.is_empty() {
             self.input_cursor = 0;
+            self.input_undo.clear();
+            self.input_redo.clear();
+            self.last_input_edit = InputEdit::None;
             return Some(self.input.drain(..).collect());
         }
         None
@@ -170,4 +772,469 @@ impl ApplicationState {
     pub fn add_message(&mut self, message: LogMessage) {
         self.messages.push(message);
     }
+
+    pub fn add_system_message(&mut self, content: String, message_type: SystemMessageType) {
+        if let SystemMessageType::Error = message_type {
+            self.error_flash = true;
+            if self.errors_view() > 0 {
+                self.unseen_errors += 1;
+            }
+        }
+        let message = LogMessage::new(
+            "termchat".into(),
+            MessageType::System(content, message_type),
+        );
+        self.add_message(message);
+    }
+}
+
+fn scroll_view(view: &mut usize, movement: ScrollMovement) {
+    match movement {
+        ScrollMovement::Up => {
+            if *view > 0 {
+                *view -= 1;
+            }
+        }
+        ScrollMovement::Down => {
+            *view += 1;
+        }
+        ScrollMovement::Start => {
+            *view += 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use message_io::network::{NetEvent, NetworkManager};
+
+    /// Endpoints to identify peers, nothing is sent through them.
+    fn endpoints(count: usize) -> Vec<Endpoint> {
+        let mut network = NetworkManager::new(|_: NetEvent<()>| ());
+        (0..count)
+            .map(|_| network.connect_udp("127.0.0.1:5877").unwrap())
+            .collect()
+    }
+
+    fn peer(user: &str) -> Peer {
+        Peer {
+            user: user.into(),
+            server_port: None,
+            version: 0,
+        }
+    }
+
+    fn state_with_input(input: &str) -> ApplicationState {
+        let mut state = ApplicationState::new();
+        for character in input.chars() {
+            state.input_write(character);
+        }
+        state
+    }
+
+    #[test]
+    fn remove_previous_word() {
+        let mut state = state_with_input("hello big world");
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "hello big ");
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "hello ");
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "");
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "");
+    }
+
+    #[test]
+    fn remove_previous_word_with_surrounding_whitespace() {
+        let mut state = state_with_input("  hello world  ");
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "  hello ");
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "  ");
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "");
+    }
+
+    #[test]
+    fn remove_previous_word_in_the_middle() {
+        let mut state = state_with_input("hello world");
+        for _ in 0.."world".len() {
+            state.input_move_cursor(CursorMovement::Left);
+        }
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "world");
+        assert_eq!(state.input_cursor(), 0);
+    }
+
+    #[test]
+    fn remove_previous_word_with_multibyte_characters() {
+        let mut state = state_with_input("añejo\u{3000}café 🎉");
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "añejo\u{3000}café ");
+        // The ideographic space also separates words
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "añejo\u{3000}");
+        state.input_move_cursor(CursorMovement::Left);
+        state.input_move_cursor(CursorMovement::Left);
+        state.input_move_cursor(CursorMovement::Left);
+        state.input_remove_previous_word();
+        assert_eq!(state.input(), "jo\u{3000}");
+        assert_eq!(state.input_cursor(), 0);
+    }
+
+    #[test]
+    fn remove_to_start() {
+        let mut state = state_with_input("añejo café");
+        for _ in 0.."café".chars().count() {
+            state.input_move_cursor(CursorMovement::Left);
+        }
+        state.input_remove_to_start();
+        assert_eq!(state.input(), "café");
+        assert_eq!(state.input_cursor(), 0);
+        state.input_remove_to_start();
+        assert_eq!(state.input(), "café");
+    }
+
+    #[test]
+    fn remove_to_end() {
+        let mut state = state_with_input("añejo café");
+        state.input_move_cursor(CursorMovement::Start);
+        state.input_move_cursor(CursorMovement::Right);
+        state.input_move_cursor(CursorMovement::Right);
+        state.input_remove_to_end();
+        assert_eq!(state.input(), "añ");
+        assert_eq!(state.input_cursor(), 2);
+        state.input_remove_to_end();
+        assert_eq!(state.input(), "añ");
+    }
+
+    #[test]
+    fn undo_removals() {
+        let mut state = state_with_input("hello world");
+        state.input_remove_previous_word();
+        state.input_remove_to_start();
+        assert_eq!(state.input(), "");
+        state.input_undo();
+        assert_eq!(state.input(), "hello ");
+        state.input_undo();
+        assert_eq!(state.input(), "hello world");
+    }
+
+    #[test]
+    fn peer_removed_in_the_middle_of_a_broadcast() {
+        let mut state = ApplicationState::new();
+        let endpoints = endpoints(3);
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            state.connected_user(*endpoint, peer(&format!("user{}", index)));
+        }
+
+        // The second user went away before its removal was processed
+        let error = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        state.remove_unreachable_peers(vec![(endpoints[1], error)], true);
+        let mut remaining = state.all_user_endpoints().copied().collect::<Vec<_>>();
+        remaining.sort_by_key(|endpoint| endpoint.resource_id());
+        assert_eq!(remaining, vec![endpoints[0], endpoints[2]]);
+        assert!(state.messages().iter().any(|message| matches!(
+            message.message_type,
+            MessageType::System(_, SystemMessageType::Warning)
+        )));
+
+        // It gets the message when reconnecting
+        let ttl = Duration::from_secs(60);
+        state.store_offline_message("hello", ttl);
+        assert_eq!(state.take_offline_messages("user1", ttl), vec!["hello"]);
+        assert!(state.take_offline_messages("user0", ttl).is_empty());
+    }
+
+    #[test]
+    fn more_users_than_the_limit() {
+        let mut state = ApplicationState::new();
+        let endpoints = endpoints(4);
+        let (loopback, users) = (endpoints[0], &endpoints[1..]);
+        state.connected_user(loopback, peer("self"));
+
+        // The announcements of the users arrive one after another
+        for (index, endpoint) in users[..2].iter().enumerate() {
+            assert!(matches!(
+                state.peer_limit(2, Some(loopback)),
+                PeerLimit::Available
+            ));
+            state.connected_user(*endpoint, peer(&format!("user{}", index)));
+        }
+        assert!(matches!(
+            state.peer_limit(2, Some(loopback)),
+            PeerLimit::Reached
+        ));
+        assert!(matches!(
+            state.peer_limit(2, Some(loopback)),
+            PeerLimit::Full
+        ));
+        assert!(matches!(
+            state.peer_limit(2, Some(loopback)),
+            PeerLimit::Full
+        ));
+
+        state.disconnected_user(users[0]);
+        assert!(matches!(
+            state.peer_limit(2, Some(loopback)),
+            PeerLimit::Available
+        ));
+        state.connected_user(users[2], peer("user2"));
+        assert!(matches!(
+            state.peer_limit(2, Some(loopback)),
+            PeerLimit::Reached
+        ));
+        assert!(matches!(
+            state.peer_limit(2, Some(loopback)),
+            PeerLimit::Full
+        ));
+    }
+
+    #[test]
+    fn offline_messages_at_once_are_not_flooding() {
+        let endpoint = endpoints(1)[0];
+        for burst in &[1, 20] {
+            let mut state = ApplicationState::new();
+            let limit = MessageRateLimit {
+                rate: 10.0,
+                burst: *burst,
+            };
+            for _ in 0..MAX_OFFLINE_MESSAGES {
+                let rate = state.limit_message_rate(endpoint, &limit);
+                assert!(!matches!(
+                    rate,
+                    MessageRate::Flooding | MessageRate::Ignored
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn messages_received_before_the_user() {
+        let endpoints = endpoints(1);
+        let mut state = ApplicationState::new();
+        state.pending_peer(endpoints[0]);
+        state.buffer_early_message(endpoints[0], "first".into());
+        state.buffer_early_message(endpoints[0], "second".into());
+        assert_eq!(state.user_name(endpoints[0]), None);
+
+        let early_messages = state.take_early_messages(endpoints[0]);
+        state.connected_user(endpoints[0], peer("alice"));
+        assert_eq!(early_messages, vec!["first", "second"]);
+        assert_eq!(state.user_name(endpoints[0]), Some(&"alice".into()));
+        assert!(state.take_early_messages(endpoints[0]).is_empty());
+        assert_eq!(state.drop_counters().messages, 0);
+    }
+
+    #[test]
+    fn too_many_messages_before_the_user() {
+        let endpoints = endpoints(1);
+        let mut state = ApplicationState::new();
+        for index in 0..MAX_EARLY_MESSAGES + 3 {
+            state.buffer_early_message(endpoints[0], index.to_string());
+        }
+        assert_eq!(state.drop_counters().messages, 3);
+
+        state.disconnecting_peer(endpoints[0], false);
+        assert_eq!(state.drop_counters().messages, MAX_EARLY_MESSAGES + 3);
+        assert!(state.take_early_messages(endpoints[0]).is_empty());
+    }
+
+    fn disconnections(state: &ApplicationState) -> usize {
+        state
+            .messages()
+            .iter()
+            .filter(|message| matches!(message.message_type, MessageType::Disconnection))
+            .count()
+    }
+
+    #[test]
+    fn connecting_to_a_user() {
+        let endpoints = endpoints(1);
+        let mut state = ApplicationState::new();
+        state.connecting_user(endpoints[0], peer("alice"));
+        assert!(matches!(
+            state.peer_state(endpoints[0]),
+            Some(PeerState::Connecting(peer)) if peer.user == "alice"
+        ));
+        assert_eq!(state.all_user_endpoints().count(), 0);
+        // Known, so it is not connected twice
+        assert_eq!(state.user_endpoints("alice").count(), 1);
+
+        state.connected_user(endpoints[0], peer("alice"));
+        assert!(matches!(
+            state.peer_state(endpoints[0]),
+            Some(PeerState::Established(peer)) if peer.user == "alice"
+        ));
+        assert_eq!(state.all_user_endpoints().count(), 1);
+    }
+
+    #[test]
+    fn connection_accepted_from_a_user() {
+        let endpoints = endpoints(1);
+        let mut state = ApplicationState::new();
+        state.pending_peer(endpoints[0]);
+        assert!(matches!(
+            state.peer_state(endpoints[0]),
+            Some(PeerState::HandshakePending(_))
+        ));
+        assert_eq!(state.all_peer_endpoints().count(), 1);
+        assert_eq!(state.all_user_endpoints().count(), 0);
+
+        state.connected_user(endpoints[0], peer("alice"));
+        assert!(matches!(
+            state.peer_state(endpoints[0]),
+            Some(PeerState::Established(peer)) if peer.user == "alice"
+        ));
+    }
+
+    #[test]
+    fn user_disconnected_by_itself() {
+        let endpoints = endpoints(1);
+        let mut state = ApplicationState::new();
+        state.connected_user(endpoints[0], peer("alice"));
+        state.remove_peer(endpoints[0], false);
+        assert!(state.peer_state(endpoints[0]).is_none());
+        assert_eq!(disconnections(&state), 1);
+    }
+
+    #[test]
+    fn user_disconnected_from_here() {
+        let endpoints = endpoints(1);
+        let mut state = ApplicationState::new();
+        state.connected_user(endpoints[0], peer("alice"));
+        state.disconnecting_peer(endpoints[0], false);
+        assert!(matches!(
+            state.peer_state(endpoints[0]),
+            Some(PeerState::Disconnecting)
+        ));
+        assert_eq!(state.all_peer_endpoints().count(), 0);
+        assert_eq!(disconnections(&state), 1);
+
+        // After its queued events
+        state.disconnected_user(endpoints[0]);
+        assert!(state.peer_state(endpoints[0]).is_none());
+        assert_eq!(disconnections(&state), 1);
+    }
+
+    #[test]
+    fn handshake_rejected_from_here() {
+        let endpoints = endpoints(1);
+        let mut state = ApplicationState::new();
+        state.buffer_early_message(endpoints[0], "hello".into());
+        state.disconnecting_peer(endpoints[0], false);
+        assert!(matches!(
+            state.peer_state(endpoints[0]),
+            Some(PeerState::Disconnecting)
+        ));
+        assert_eq!(state.drop_counters().messages, 1);
+        assert_eq!(disconnections(&state), 0);
+    }
+
+    #[test]
+    fn peer_transitions_in_debug_mode() {
+        let endpoints = endpoints(1);
+        let mut state = ApplicationState::new();
+        state.toggle_debug();
+        state.connecting_user(endpoints[0], peer("alice"));
+        state.connected_user(endpoints[0], peer("alice"));
+        state.disconnecting_peer(endpoints[0], false);
+        state.disconnected_user(endpoints[0]);
+        state.disconnected_user(endpoints[0]);
+
+        let transitions = state
+            .messages()
+            .iter()
+            .filter_map(|message| match &message.message_type {
+                MessageType::System(info, SystemMessageType::Info) => {
+                    info.split(": ").nth(1).map(String::from)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            transitions,
+            vec![
+                "Untracked -> Connecting",
+                "Connecting -> Established",
+                "Established -> Disconnecting",
+                "Disconnecting -> Untracked",
+            ]
+        );
+    }
+
+    #[test]
+    fn alias_of_only_one_user() {
+        let endpoints = endpoints(2);
+        let mut state = ApplicationState::new();
+        state.connected_user(endpoints[0], peer("alice"));
+        state.connected_user(endpoints[1], peer("bob"));
+        assert!(state.is_alias_available("alice", "boss"));
+        state.set_alias("alice".into(), Some("boss".into()));
+        assert_eq!(state.shown_name("alice"), "boss");
+        assert_eq!(state.user_by_name("boss"), "alice");
+
+        // Neither the name nor the alias of another user
+        assert!(!state.is_alias_available("bob", "boss"));
+        assert!(!state.is_alias_available("bob", "alice"));
+        assert!(state.is_alias_available("bob", "bob"));
+        // The own alias can be given again
+        assert!(state.is_alias_available("alice", "boss"));
+
+        state.set_alias("alice".into(), None);
+        assert!(state.is_alias_available("bob", "boss"));
+        assert_eq!(state.user_by_name("boss"), "boss");
+    }
+
+    #[test]
+    fn same_user_from_several_applications_in_a_host() {
+        let endpoints = endpoints(3);
+        let mut state = ApplicationState::new();
+        let ports = [Some(5877), Some(3001), None]; // The last one of an older version
+        for (endpoint, server_port) in endpoints.iter().zip(&ports) {
+            let peer = Peer {
+                user: "alice".into(),
+                server_port: *server_port,
+                version: 0,
+            };
+            state.connected_user(*endpoint, peer);
+        }
+
+        let server_addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            state.server_endpoints(server_addr("127.0.0.1:5877")),
+            vec![endpoints[0]]
+        );
+        assert_eq!(
+            state.server_endpoints(server_addr("127.0.0.1:3001")),
+            vec![endpoints[1]]
+        );
+        assert!(state
+            .server_endpoints(server_addr("127.0.0.1:3002"))
+            .is_empty());
+        assert!(state
+            .server_endpoints(server_addr("10.0.0.1:5877"))
+            .is_empty());
+    }
+
+    #[test]
+    fn version_of_the_peers() {
+        let endpoints = endpoints(3);
+        let mut state = ApplicationState::new();
+        let mut newer = peer("alice");
+        newer.version = 1;
+        state.connecting_user(endpoints[0], newer.clone());
+        assert_eq!(state.peer_version(endpoints[0]), 1);
+        state.connected_user(endpoints[0], newer);
+        assert_eq!(state.peer_version(endpoints[0]), 1);
+
+        // Without a version, only the first messages of the protocol are sent to it
+        state.connected_user(endpoints[1], peer("bob"));
+        assert_eq!(state.peer_version(endpoints[1]), 0);
+        state.pending_peer(endpoints[2]);
+        assert_eq!(state.peer_version(endpoints[2]), 0);
+    }
 }
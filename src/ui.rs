@@ -1,5 +1,7 @@
-use super::state::{ApplicationState, MessageType};
-use super::util::SplitEach;
+use super::commands::{self, InputKind};
+use super::config::{ActivityPanelSide, Config, MessageIcons, Presence};
+use super::state::{ApplicationState, LogMessage, MessageLayout, MessageType, SystemMessageType};
+use super::util;
 
 use tui::backend::CrosstermBackend;
 use tui::layout::{Alignment, Constraint, Direction, Layout, Rect};
@@ -8,97 +10,389 @@ use tui::text::{Span, Spans};
 use tui::widgets::{Block, Borders, Paragraph, Wrap};
 use tui::{Frame, Terminal};
 
-use std::io::Stdout;
+use std::io::{self, Stdout};
 
-pub fn draw(terminal: &mut Terminal<CrosstermBackend<Stdout>>, state: &ApplicationState) {
-    terminal
-        .draw(|frame| {
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints([Constraint::Min(0), Constraint::Length(6)].as_ref())
-                .split(frame.size());
+const MESSAGE_COLORS: [Color; 5] = [
+    Color::Blue,
+    Color::Yellow,
+    Color::Red,
+    Color::Cyan,
+    Color::Magenta,
+];
 
-            draw_messages_panel(frame, state, chunks[0]);
-            draw_input_panel(frame, state, chunks[1]);
-        })
-        .unwrap()
+const MESSAGE_GROUP_TIME_GAP: i64 = 300; //seconds
+const USERS_PANEL_WIDTH: u16 = 24;
+const MIN_WIDTH_FOR_USERS_PANEL: u16 = 60; // Narrower terminals hide the users panel
+const MIN_WIDTH_FOR_ACTIVITY_PANEL: u16 = 80; // Only when it is at the right
+const MIN_HEIGHT_FOR_ACTIVITY_PANEL: u16 = 24; // Only when it is below
+
+/// Messages shown by a messages panel.
+enum PanelContent {
+    All,
+    Chat,     // Everything except the system messages
+    Activity, // Only the system messages
+}
+
+pub fn draw(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    state: &ApplicationState,
+    config: &Config,
+) -> io::Result<()> {
+    terminal.draw(|frame| {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(6)].as_ref())
+            .split(frame.size());
+
+        if state.users_panel_visible() && frame.size().width >= MIN_WIDTH_FOR_USERS_PANEL {
+            let upper_chunks = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(0), Constraint::Length(USERS_PANEL_WIDTH)].as_ref())
+                .split(chunks[0]);
+
+            draw_room(frame, state, config, upper_chunks[0]);
+            draw_users_panel(frame, state, config, upper_chunks[1]);
+        } else {
+            draw_room(frame, state, config, chunks[0]);
+        }
+        draw_input_panel(frame, state, config, chunks[1]);
+    })
+}
+
+fn user_color(state: &ApplicationState, user: &str) -> Color {
+    match state.users_id().get(user) {
+        Some(id) => MESSAGE_COLORS[id % MESSAGE_COLORS.len()],
+        None => Color::Green, //because is the own user
+    }
+}
+
+/// Draws the messages, split in the chat and activity panels if configured and there is room.
+fn draw_room(
+    frame: &mut Frame<CrosstermBackend<Stdout>>,
+    state: &ApplicationState,
+    config: &Config,
+    chunk: Rect,
+) {
+    let activity_panel = match &config.activity_panel {
+        Some(activity_panel) => activity_panel,
+        None => return draw_messages_panel(frame, state, config, chunk, PanelContent::All),
+    };
+    let (direction, fits) = match activity_panel.side {
+        ActivityPanelSide::Right => (
+            Direction::Horizontal,
+            chunk.width >= MIN_WIDTH_FOR_ACTIVITY_PANEL,
+        ),
+        ActivityPanelSide::Below => (
+            Direction::Vertical,
+            chunk.height >= MIN_HEIGHT_FOR_ACTIVITY_PANEL,
+        ),
+    };
+    if !fits {
+        return draw_messages_panel(frame, state, config, chunk, PanelContent::All);
+    }
+
+    let chunks = Layout::default()
+        .direction(direction)
+        .constraints(
+            [
+                Constraint::Percentage(activity_panel.chat_percentage),
+                Constraint::Percentage(100 - activity_panel.chat_percentage),
+            ]
+            .as_ref(),
+        )
+        .split(chunk);
+
+    draw_messages_panel(frame, state, config, chunks[0], PanelContent::Chat);
+    draw_messages_panel(frame, state, config, chunks[1], PanelContent::Activity);
 }
 
 fn draw_messages_panel(
     frame: &mut Frame<CrosstermBackend<Stdout>>,
     state: &ApplicationState,
+    config: &Config,
     chunk: Rect,
+    content: PanelContent,
 ) {
-    const MESSAGE_COLORS: [Color; 5] = [
-        Color::Blue,
-        Color::Yellow,
-        Color::Red,
-        Color::Cyan,
-        Color::Magenta,
-    ];
-
-    let messages = state
+    let compact = matches!(state.message_layout(), MessageLayout::Compact);
+    let mut previous_message = None;
+    let mut messages = state
         .messages()
         .iter()
-        .rev()
+        .filter(|message| {
+            let system = matches!(
+                message.message_type,
+                MessageType::System(..) | MessageType::Command(_)
+            );
+            match content {
+                PanelContent::All => true,
+                PanelContent::Chat => !system,
+                PanelContent::Activity => system,
+            }
+        })
         .map(|message| {
-            let color = if let Some(id) = state.users_id().get(&message.user) {
-                MESSAGE_COLORS[id % MESSAGE_COLORS.len()]
-            } else {
-                Color::Green //because is a message of the own user
-            };
+            let grouped = compact && is_grouped(previous_message, message);
+            previous_message = Some(message);
+
+            let color = user_color(state, &message.user);
+            let user = state.shown_name(&message.user);
             let date = message.date.format("%H:%M:%S ").to_string();
-            match &message.message_type {
+            let mut line = match &message.message_type {
+                MessageType::Content(content) | MessageType::ContentSent(content) if grouped => {
+                    Spans::from(vec![
+                        Span::raw(" ".repeat(date.len() + 2)),
+                        Span::raw(content),
+                    ])
+                }
                 MessageType::Connection => Spans::from(vec![
                     Span::styled(date, Style::default().fg(Color::DarkGray)),
-                    Span::styled(&message.user, Style::default().fg(color)),
+                    Span::styled(user, Style::default().fg(color)),
                     Span::styled(" is online", Style::default().fg(color)),
                 ]),
                 MessageType::Disconnection => Spans::from(vec![
                     Span::styled(date, Style::default().fg(Color::DarkGray)),
-                    Span::styled(&message.user, Style::default().fg(color)),
+                    Span::styled(user, Style::default().fg(color)),
                     Span::styled(" is offline", Style::default().fg(color)),
                 ]),
-                MessageType::Content(content) => Spans::from(vec![
+                MessageType::Content(content) | MessageType::ContentSent(content) => {
+                    Spans::from(vec![
+                        Span::styled(date, Style::default().fg(Color::DarkGray)),
+                        Span::styled(user, Style::default().fg(color)),
+                        Span::styled(": ", Style::default().fg(color)),
+                        Span::raw(content),
+                    ])
+                }
+                MessageType::WhisperReceived(content) => Spans::from(vec![
                     Span::styled(date, Style::default().fg(Color::DarkGray)),
-                    Span::styled(&message.user, Style::default().fg(color)),
-                    Span::styled(": ", Style::default().fg(color)),
-                    Span::raw(content),
+                    Span::styled(
+                        format!("[whisper from {}] ", user),
+                        Style::default().fg(color).add_modifier(Modifier::ITALIC),
+                    ),
+                    Span::styled(content, Style::default().add_modifier(Modifier::ITALIC)),
                 ]),
+                MessageType::WhisperSent(content) => Spans::from(vec![
+                    Span::styled(date, Style::default().fg(Color::DarkGray)),
+                    Span::styled(
+                        format!("[whisper to {}] ", user),
+                        Style::default().fg(color).add_modifier(Modifier::ITALIC),
+                    ),
+                    Span::styled(content, Style::default().add_modifier(Modifier::ITALIC)),
+                ]),
+                MessageType::Command(input) => Spans::from(vec![
+                    Span::styled(date, Style::default().fg(Color::DarkGray)),
+                    Span::styled("> ", Style::default().fg(Color::Cyan)),
+                    Span::styled(input, Style::default().fg(Color::Cyan)),
+                ]),
+                MessageType::System(content, message_type) => {
+                    let color = match message_type {
+                        SystemMessageType::Info => Color::Gray,
+                        SystemMessageType::Warning => Color::Yellow,
+                        SystemMessageType::Error => Color::Red,
+                    };
+                    Spans::from(vec![
+                        Span::styled(date, Style::default().fg(Color::DarkGray)),
+                        Span::styled(user, Style::default().fg(color)),
+                        Span::styled(": ", Style::default().fg(color)),
+                        Span::styled(content, Style::default().fg(color)),
+                    ])
+                }
+            };
+            if let Some(icons) = &config.message_icons {
+                // A grouped message is aligned with the content of the first one
+                let icon = if grouped {
+                    " "
+                } else {
+                    message_icon(message, icons)
+                };
+                let icon = Span::styled(format!("{} ", icon), Style::default().fg(Color::DarkGray));
+                line.0.insert(1, icon);
             }
+            line
         })
         .collect::<Vec<_>>();
+    messages.reverse();
 
-    let messages_panel = Paragraph::new(messages)
-        .block(Block::default().borders(Borders::ALL).title(Span::styled(
-            "LAN Room",
+    let (name, scroll, shows_errors) = match content {
+        PanelContent::All => ("LAN Room", state.scroll_messages_view(), true),
+        PanelContent::Chat => ("LAN Room", state.scroll_messages_view(), false),
+        PanelContent::Activity => ("Activity", state.scroll_activity_view(), true),
+    };
+    let title = match state.unseen_errors() {
+        _ if !shows_errors => name.into(),
+        0 => name.into(),
+        1 => format!("{} - 1 new error", name),
+        errors => format!("{} - {} new errors", name, errors),
+    };
+    let (title_style, border_style) = if shows_errors && state.error_flash() {
+        (
+            Style::default().add_modifier(Modifier::BOLD | Modifier::REVERSED),
+            Style::default().fg(Color::Red),
+        )
+    } else {
+        (
             Style::default().add_modifier(Modifier::BOLD),
-        )))
+            Style::default(),
+        )
+    };
+
+    let messages_panel = Paragraph::new(messages)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(border_style)
+                .title(Span::styled(title, title_style)),
+        )
         .style(Style::default().fg(Color::White))
         .alignment(Alignment::Left)
-        .scroll((state.scroll_messages_view() as u16, 0))
+        .scroll((scroll as u16, 0))
         .wrap(Wrap { trim: false });
 
     frame.render_widget(messages_panel, chunk);
 }
 
+fn message_icon(message: &LogMessage, icons: &MessageIcons) -> &'static str {
+    let (unicode, ascii) = match &message.message_type {
+        MessageType::ContentSent(_) | MessageType::WhisperSent(_) | MessageType::Command(_) => {
+            ("→", ">")
+        }
+        MessageType::Content(_) | MessageType::WhisperReceived(_) => ("←", "<"),
+        MessageType::Connection => ("●", "+"),
+        MessageType::Disconnection => ("○", "-"),
+        MessageType::System(_, SystemMessageType::Info) => ("•", "*"),
+        MessageType::System(_, SystemMessageType::Warning) => ("▲", "!"),
+        MessageType::System(_, SystemMessageType::Error) => ("✗", "x"),
+    };
+    match icons {
+        MessageIcons::Unicode => unicode,
+        MessageIcons::Ascii => ascii,
+    }
+}
+
+/// Checks if the message continues the group of the previous one:
+/// both are contents of the same user not too far apart in time.
+fn is_grouped(previous: Option<&LogMessage>, message: &LogMessage) -> bool {
+    match (previous, &message.message_type) {
+        (Some(previous), MessageType::Content(_))
+        | (Some(previous), MessageType::ContentSent(_)) => {
+            matches!(
+                previous.message_type,
+                MessageType::Content(_) | MessageType::ContentSent(_)
+            ) && previous.user == message.user
+                && message.date - previous.date < chrono::Duration::seconds(MESSAGE_GROUP_TIME_GAP)
+        }
+        _ => false,
+    }
+}
+
+fn draw_users_panel(
+    frame: &mut Frame<CrosstermBackend<Stdout>>,
+    state: &ApplicationState,
+    config: &Config,
+    chunk: Rect,
+) {
+    let mut users = state.all_users_activity().collect::<Vec<_>>();
+    users.sort_by_key(|(_, user, _)| state.shown_name(user));
+    let title = format!("Users ({})", users.len());
+
+    let thresholds = &config.presence_thresholds;
+    let users = users
+        .into_iter()
+        .flat_map(|(endpoint, user, silence)| {
+            let presence = match thresholds.presence(silence) {
+                Presence::Active => "",
+                Presence::Idle => " (idle)",
+                Presence::Stale => " (stale)",
+            };
+            let shown_name = state.shown_name(user);
+            // The real name is kept visible, so an alias is not mistaken for another user
+            let real_name = if shown_name != user {
+                format!(" ({})", user)
+            } else {
+                String::new()
+            };
+            let mut lines = vec![Spans::from(vec![
+                Span::styled(shown_name, Style::default().fg(user_color(state, user))),
+                Span::styled(real_name, Style::default().fg(Color::DarkGray)),
+                Span::styled(presence, Style::default().fg(Color::DarkGray)),
+            ])];
+            if let Some(status) = state.user_status(endpoint) {
+                lines.push(Spans::from(Span::styled(
+                    format!("  {}", status),
+                    Style::default()
+                        .fg(Color::DarkGray)
+                        .add_modifier(Modifier::ITALIC),
+                )));
+            }
+            lines
+        })
+        .collect::<Vec<_>>();
+
+    let users_panel = Paragraph::new(users)
+        .block(Block::default().borders(Borders::ALL).title(Span::styled(
+            title,
+            Style::default().add_modifier(Modifier::BOLD),
+        )))
+        .style(Style::default().fg(Color::White))
+        .alignment(Alignment::Left);
+
+    frame.render_widget(users_panel, chunk);
+}
+
 fn draw_input_panel(
     frame: &mut Frame<CrosstermBackend<Stdout>>,
     state: &ApplicationState,
+    config: &Config,
     chunk: Rect,
 ) {
     let inner_width = (chunk.width - 2) as usize;
 
-    let input = state
-        .input()
-        .split_each(inner_width)
-        .iter()
-        .map(|line| Spans::from(vec![Span::raw(*line)]))
-        .collect::<Vec<_>>();
+    let (title, input_style) = match commands::input_kind(state.input(), config) {
+        InputKind::Text => ("Your message".into(), Style::default()),
+        InputKind::Command(usage) => (
+            format!("Command: {}", usage),
+            Style::default().fg(Color::Cyan),
+        ),
+        InputKind::UnknownCommand => ("Unknown command".into(), Style::default().fg(Color::Red)),
+    };
+
+    let prompt = &config.input_prompt;
+    let prompt_style = Style::default().fg(Color::DarkGray);
+    let text = format!("{}{}", prompt, state.input());
+    let cursor = prompt.chars().count() + state.input_cursor();
+    let (lines, (cursor_line, cursor_column)) = util::wrap_text(&text, inner_width, cursor);
+    let input = if state.input().is_empty() {
+        let placeholder = format!(
+            "Type a message, or a command starting with '{}'",
+            config.command_prefix
+        );
+        vec![Spans::from(vec![
+            Span::styled(prompt.as_str(), prompt_style),
+            Span::styled(
+                placeholder,
+                Style::default()
+                    .fg(Color::DarkGray)
+                    .add_modifier(Modifier::ITALIC),
+            ),
+        ])]
+    } else {
+        let mut line_start = 0;
+        lines
+            .iter()
+            .map(|line| {
+                // The prompt could take more than one line in narrow terminals
+                let prompt_len = prompt.len().saturating_sub(line_start);
+                let (prompt_part, input_part) = line.split_at(prompt_len.min(line.len()));
+                line_start += line.len();
+                Spans::from(vec![
+                    Span::styled(prompt_part, prompt_style),
+                    Span::styled(input_part, input_style),
+                ])
+            })
+            .collect::<Vec<_>>()
+    };
 
     let input_panel = Paragraph::new(input)
         .block(Block::default().borders(Borders::ALL).title(Span::styled(
-            "Your message",
+            title,
             Style::default().add_modifier(Modifier::BOLD),
         )))
         .style(Style::default().fg(Color::White))
@@ -107,7 +401,7 @@ fn draw_input_panel(
     frame.render_widget(input_panel, chunk);
 
     frame.set_cursor(
-        chunk.x + 1 + (state.input_cursor() % inner_width) as u16,
-        chunk.y + 1 + (state.input_cursor() / inner_width) as u16,
+        chunk.x + 1 + cursor_column as u16,
+        chunk.y + 1 + cursor_line as u16,
     )
 }
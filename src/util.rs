@@ -1,17 +1,404 @@
-pub trait SplitEach {
-    fn split_each(&self, n: usize) -> Vec<&Self>;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+
+use unicode_width::UnicodeWidthChar;
+
+use std::fmt;
+use std::io::{self};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::ops::RangeInclusive;
+use std::time::Instant;
+
+/// Splits the text in lines of `width` columns, by the width the characters take in a terminal.
+/// Returns also the line and column of the position after `cursor` characters,
+/// that goes to the start of a new line if the last one is full.
+pub fn wrap_text(text: &str, width: usize, cursor: usize) -> (Vec<&str>, (usize, usize)) {
+    let mut lines = Vec::new();
+    let (mut line_start, mut column) = (0, 0);
+    let mut cursor_position = None;
+    for (position, (index, character)) in text.char_indices().enumerate() {
+        let character_width = character.width().unwrap_or(0);
+        // A character wider than the whole line is left alone in it
+        if column + character_width > width && column > 0 {
+            lines.push(&text[line_start..index]);
+            line_start = index;
+            column = 0;
+        }
+        if position == cursor {
+            cursor_position = Some((lines.len(), column));
+        }
+        column += character_width;
+    }
+    if line_start < text.len() {
+        lines.push(&text[line_start..]);
+    }
+
+    let cursor_position = cursor_position.unwrap_or(if column >= width && column > 0 {
+        (lines.len(), 0)
+    } else {
+        (lines.len().saturating_sub(1), column)
+    });
+    (lines, cursor_position)
+}
+
+/// Allows `burst` actions at once, refilled at `rate` actions per second.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
 }
 
-impl SplitEach for str {
-    fn split_each(&self, n: usize) -> Vec<&str> {
-        let mut splitted =
-            Vec::with_capacity(self.len() / n + if self.len() % n > 0 { 1 } else { 0 });
-        let mut last = self;
-        while !last.is_empty() {
-            let (chunk, rest) = last.split_at(std::cmp::min(n, last.len()));
-            splitted.push(chunk);
-            last = rest;
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Returns `false` if there are no tokens left for the action.
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub const MAX_USER_NAME_LEN: usize = 32;
+pub const MAX_STATUS_LEN: usize = 64;
+pub const MAX_MOTD_LEN: usize = 256;
+
+/// Removes from the name anything that could break the terminal or a file path:
+/// control characters, ANSI escape sequences and path separators.
+/// The name is trimmed and limited to `MAX_USER_NAME_LEN` characters.
+/// Returns `None` if nothing valid remains.
+pub fn sanitize_user_name(name: &str) -> Option<String> {
+    sanitize(name, MAX_USER_NAME_LEN, true)
+}
+
+/// Like `sanitize_user_name` but keeping the path separators,
+/// limited to `MAX_STATUS_LEN` characters.
+pub fn sanitize_status(status: &str) -> Option<String> {
+    sanitize(status, MAX_STATUS_LEN, false)
+}
+
+/// Like `sanitize_status`, limited to `MAX_MOTD_LEN` characters.
+pub fn sanitize_motd(motd: &str) -> Option<String> {
+    sanitize(motd, MAX_MOTD_LEN, false)
+}
+
+fn sanitize(text: &str, max_len: usize, path_safe: bool) -> Option<String> {
+    let mut sanitized = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(character) = chars.next() {
+        if character == '\x1b' {
+            // Skip a CSI sequence ("ESC [ params final") entirely, not only its ESC.
+            if chars.peek() == Some(&'[') {
+                chars.next();
+                for sequence_char in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&sequence_char) {
+                        break;
+                    }
+                }
+            }
+        } else if !character.is_control() && !(path_safe && (character == '/' || character == '\\'))
+        {
+            sanitized.push(character);
+        }
+    }
+
+    let sanitized = sanitized.trim().chars().take(max_len).collect::<String>();
+    let sanitized = sanitized.trim_end();
+    if sanitized.is_empty() {
+        None
+    } else {
+        Some(sanitized.into())
+    }
+}
+
+/// Address of the local interface that the OS uses to reach the given address.
+pub fn local_ip_towards(addr: SocketAddr) -> io::Result<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Network interface of this machine, one per address.
+#[cfg_attr(not(unix), allow(dead_code))] // Only listed in unix
+pub struct Interface {
+    pub name: String,
+    pub ip: IpAddr,
+    pub up: bool,
+    pub multicast: bool, // Can join multicast groups
+}
+
+/// Lists the addresses of the local network interfaces.
+#[cfg(unix)]
+pub fn local_interfaces() -> io::Result<Vec<Interface>> {
+    let mut first_addr = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut first_addr) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut interfaces = Vec::new();
+    let mut next_addr = first_addr;
+    while let Some(if_addr) = unsafe { next_addr.as_ref() } {
+        // Only the ones with an ip address, not the link layer ones
+        if let Some(ip) = unsafe { ip_from_sockaddr(if_addr.ifa_addr) } {
+            let name = unsafe { std::ffi::CStr::from_ptr(if_addr.ifa_name) };
+            interfaces.push(Interface {
+                name: name.to_string_lossy().into_owned(),
+                ip,
+                up: if_addr.ifa_flags & libc::IFF_UP as libc::c_uint != 0,
+                multicast: if_addr.ifa_flags & libc::IFF_MULTICAST as libc::c_uint != 0,
+            });
+        }
+        next_addr = if_addr.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(first_addr) };
+    Ok(interfaces)
+}
+
+#[cfg(not(unix))]
+pub fn local_interfaces() -> io::Result<Vec<Interface>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "not supported in this platform",
+    ))
+}
+
+#[cfg(unix)]
+unsafe fn ip_from_sockaddr(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    match addr.as_ref()?.sa_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = &*(addr as *const libc::sockaddr_in);
+            Some(std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into())
+        }
+        libc::AF_INET6 => {
+            let addr = &*(addr as *const libc::sockaddr_in6);
+            Some(std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr).into())
+        }
+        _ => None,
+    }
+}
+
+/// Deserializes a text replacing the invalid UTF-8 sequences instead of failing,
+/// so a malformed text from the network does not discard the whole message.
+/// To use with `#[serde(deserialize_with = "util::deserialize_lossy_string")]`.
+pub fn deserialize_lossy_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct LossyStringVisitor;
+
+    impl<'de> Visitor<'de> for LossyStringVisitor {
+        type Value = String;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a text")
         }
-        splitted
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<String, E> {
+            Ok(value.into())
+        }
+
+        fn visit_string<E: de::Error>(self, value: String) -> Result<String, E> {
+            Ok(value)
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<String, E> {
+            Ok(String::from_utf8_lossy(value).into_owned())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<String, E> {
+            match String::from_utf8(value) {
+                Ok(text) => Ok(text),
+                Err(err) => Ok(String::from_utf8_lossy(err.as_bytes()).into_owned()),
+            }
+        }
+    }
+
+    // A string and a byte buffer share the same encoding,
+    // so the bytes can be read without validating them first.
+    deserializer.deserialize_byte_buf(LossyStringVisitor)
+}
+
+/// Deserializes a field added at the end of a message, or its default value if it is missing,
+/// because an older application sent the message without it.
+/// bincode ignores the trailing bytes, so the older applications can still read the message.
+/// To use with `#[serde(deserialize_with = "util::deserialize_trailing")]` in the last fields.
+pub fn deserialize_trailing<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(T::deserialize(deserializer).unwrap_or_default())
+}
+
+/// Parses a port (`5000`) or an inclusive range of ports (`5000-5010`).
+/// Port 0, meaning any free port, is only valid alone.
+pub fn parse_port_range(text: &str) -> Option<RangeInclusive<u16>> {
+    let (first, last) = match text.find('-') {
+        Some(separator) => (&text[..separator], &text[separator + 1..]),
+        None => (text, text),
+    };
+    let (first, last) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+    match (first, last) {
+        (0, 0) => Some(0..=0),
+        (0, _) => None,
+        _ if first <= last => Some(first..=last),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct Text(#[serde(deserialize_with = "deserialize_lossy_string")] String);
+
+    #[test]
+    fn user_name_without_control_characters() {
+        assert_eq!(sanitize_user_name("al\x07ice\r\n"), Some("alice".into()));
+        assert_eq!(sanitize_user_name("bob\x00\x08\t"), Some("bob".into()));
+    }
+
+    #[test]
+    fn user_name_without_escape_sequences() {
+        assert_eq!(
+            sanitize_user_name("\x1b[31mmallory\x1b[0m"),
+            Some("mallory".into())
+        );
+        assert_eq!(sanitize_user_name("eve\x1b[2J\x1b[H"), Some("eve".into()));
+        // A lone ESC is removed without eating the following characters
+        assert_eq!(sanitize_user_name("\x1bcarol"), Some("carol".into()));
+    }
+
+    #[test]
+    fn user_name_without_path_separators() {
+        assert_eq!(
+            sanitize_user_name("../../etc/passwd"),
+            Some("....etcpasswd".into())
+        );
+        assert_eq!(sanitize_user_name("C:\\Users"), Some("C:Users".into()));
+    }
+
+    #[test]
+    fn user_name_limited_in_length() {
+        let long_name = "a".repeat(MAX_USER_NAME_LEN * 2);
+        assert_eq!(
+            sanitize_user_name(&long_name),
+            Some("a".repeat(MAX_USER_NAME_LEN))
+        );
+        // Counted in characters, not bytes
+        let long_name = "ñ".repeat(MAX_USER_NAME_LEN + 1);
+        assert_eq!(
+            sanitize_user_name(&long_name),
+            Some("ñ".repeat(MAX_USER_NAME_LEN))
+        );
+    }
+
+    #[test]
+    fn empty_user_name() {
+        assert_eq!(sanitize_user_name(""), None);
+        assert_eq!(sanitize_user_name("   "), None);
+        assert_eq!(sanitize_user_name("\x1b[1m\x07/\\"), None);
+    }
+
+    #[test]
+    fn lossy_string_with_emojis() {
+        let content = "hello 👋 ñandú 🦀".to_string();
+        let data = bincode::serialize(&Text(content.clone())).unwrap();
+        let Text(received) = bincode::deserialize(&data).unwrap();
+        assert_eq!(received, content);
+    }
+
+    #[test]
+    fn lossy_string_with_invalid_utf8() {
+        // Same encoding as a string: the length followed by the bytes
+        let data = bincode::serialize(&vec![b'h', b'i', 0xff, 0xfe, b'!']).unwrap();
+        assert!(bincode::deserialize::<String>(&data).is_err());
+        let Text(received) = bincode::deserialize(&data).unwrap();
+        assert_eq!(received, "hi\u{fffd}\u{fffd}!");
+    }
+
+    #[test]
+    fn wrap_text_in_one_line() {
+        let text = format!("> a{}", "é".repeat(40));
+        let (lines, cursor) = wrap_text(&text, 78, 43);
+        assert_eq!(lines, vec![text.as_str()]);
+        assert_eq!(cursor, (0, 43));
+    }
+
+    #[test]
+    fn wrap_multi_byte_text() {
+        let text = format!("> a{}", "é".repeat(20));
+        let (lines, cursor) = wrap_text(&text, 10, 23);
+        let rest = "é".repeat(3);
+        let expected = vec!["> aééééééé", "éééééééééé", rest.as_str()];
+        assert_eq!(lines, expected);
+        assert_eq!(cursor, (2, 3));
+
+        assert_eq!(wrap_text(&text, 10, 12).1, (1, 2));
+        assert_eq!(wrap_text(&text[..text.len() - 6], 10, 20).1, (2, 0));
+    }
+
+    #[test]
+    fn wrap_wide_characters() {
+        let (lines, cursor) = wrap_text("> 你好世界", 5, 5);
+        assert_eq!(lines, vec!["> 你", "好世", "界"]);
+        assert_eq!(cursor, (2, 0));
+    }
+
+    #[test]
+    fn wrap_empty_text() {
+        assert_eq!(wrap_text("", 10, 0), (vec![], (0, 0)));
+    }
+
+    #[test]
+    fn single_port() {
+        assert_eq!(parse_port_range("5000"), Some(5000..=5000));
+        assert_eq!(parse_port_range(" 5000 "), Some(5000..=5000));
+        assert_eq!(parse_port_range("0"), Some(0..=0));
+    }
+
+    #[test]
+    fn range_of_ports() {
+        assert_eq!(parse_port_range("5000-5010"), Some(5000..=5010));
+        assert_eq!(parse_port_range("5000 - 5010"), Some(5000..=5010));
+        assert_eq!(parse_port_range("5000-5000"), Some(5000..=5000));
+        assert_eq!(parse_port_range("0-0"), Some(0..=0));
+    }
+
+    #[test]
+    fn invalid_port_range() {
+        assert_eq!(parse_port_range("5010-5000"), None);
+        assert_eq!(parse_port_range("0-5000"), None);
+        assert_eq!(parse_port_range("5000-"), None);
+        assert_eq!(parse_port_range("-5000"), None);
+        assert_eq!(parse_port_range("5000-5010-5020"), None);
+        assert_eq!(parse_port_range("70000"), None);
+        assert_eq!(parse_port_range("port"), None);
+        assert_eq!(parse_port_range(""), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn loopback_interface_listed() {
+        let interfaces = local_interfaces().unwrap();
+        assert!(interfaces
+            .iter()
+            .any(|interface| interface.ip.is_loopback() && interface.up));
     }
 }